@@ -0,0 +1,7 @@
+fn main() {
+    let mut conf = prost_build::Config::new();
+    let mut gen = twirp_rs::TwirpServiceGenerator::both();
+    gen.generate_async = true;
+    conf.service_generator(Box::new(gen));
+    conf.compile_protos(&["service.proto"], &["../"]).unwrap();
+}
@@ -0,0 +1,8 @@
+//! The example service generated with `async fn` methods
+
+#[macro_use]
+extern crate prost_derive;
+
+pub mod service {
+    include!(concat!(env!("OUT_DIR"), "/twitch.twirp.example.rs"));
+}
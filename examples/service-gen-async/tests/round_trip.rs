@@ -0,0 +1,36 @@
+use futures::Future;
+use hyper::{Client, Server};
+use twirp_rs::{HyperClient, ServiceResponse, TwirpError};
+
+use example_service_gen_async::service::{self, Haberdasher, HaberdasherClient};
+
+struct TestHaberdasher;
+
+#[twirp_rs::async_trait]
+impl Haberdasher for TestHaberdasher {
+    async fn make_hat(&self, i: service::PTReq<service::Size>) -> service::PTResult<service::Hat> {
+        if i.input.inches <= 0 {
+            return Err(TwirpError::invalid_argument_field("inches", "must be positive").into());
+        }
+        Ok(ServiceResponse::new(service::Hat { size: i.input.inches, color: "blue".to_string(), name: "fedora".to_string() }))
+    }
+}
+
+#[test]
+fn async_round_trip() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(<dyn Haberdasher>::new_server(TestHaberdasher));
+    let client = HaberdasherClient::new(HyperClient::new(Client::new(), &format!("http://{}", server.local_addr())));
+    runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+
+    // The async client runs on the tokio 0.1 runtime through the same bridge as generated handlers
+    let make_hat = |inches| {
+        let client = client.clone();
+        twirp_rs::from_async(async move { client.make_hat(service::Size { inches }.into()).await })
+    };
+    let resp = runtime.block_on(make_hat(12)).unwrap();
+    assert_eq!(resp.output, service::Hat { size: 12, color: "blue".to_string(), name: "fedora".to_string() });
+
+    let err = runtime.block_on(make_hat(0)).unwrap_err();
+    assert_eq!(err.twirp_code(), Some("invalid_argument"));
+}
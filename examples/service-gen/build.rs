@@ -3,6 +3,10 @@ extern crate twirp_rs;
 
 fn main() {
     let mut conf = prost_build::Config::new();
-    conf.service_generator(Box::new(twirp_rs::TwirpServiceGenerator::new()));
-    conf.compile_protos(&["service.proto"], &["../"]).unwrap();
+    let mut gen = twirp_rs::TwirpServiceGenerator::both();
+    gen.generate_json = true;
+    gen.generate_classify = true;
+    conf.type_attribute(".", "#[derive(Serialize, Deserialize)] #[serde(default)]");
+    conf.service_generator(Box::new(gen));
+    conf.compile_protos(&["service.proto", "wardrobe.proto"], &["../"]).unwrap();
 }
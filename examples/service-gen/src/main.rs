@@ -1,27 +1,31 @@
 extern crate futures;
 #[macro_use]
 extern crate prost_derive;
+#[macro_use]
+extern crate serde_derive;
 extern crate twirp_rs;
 
 use futures::Future;
-use futures::future;
-use futures::sync::oneshot;
-use hyper::{Client, Server};
-use std::env;
-use std::thread;
-use std::time::Duration;
+use hyper::Server;
+use twirp_rs::IntoRes;
 
 extern crate prost;
 extern crate hyper;
 
+// Only the server side of the generated code is used here
+#[allow(dead_code)]
 mod service {
     include!(concat!(env!("OUT_DIR"), "/twitch.twirp.example.rs"));
+
+    pub mod wardrobe {
+        include!(concat!(env!("OUT_DIR"), "/twitch.twirp.example.wardrobe.rs"));
+    }
 }
 
 fn main() {
     println!("Starting server");
     let addr = "0.0.0.0:8080".parse().unwrap();
-    let service = service::Haberdasher::new_server(HaberdasherService);
+    let service = <dyn service::Haberdasher>::new_server(HaberdasherService);
     let server = Server::bind(&addr).serve(service).map_err(|e| eprintln!("server error: {}", e));
 
     hyper::rt::run(server);
@@ -30,8 +34,6 @@ fn main() {
 pub struct HaberdasherService;
 impl service::Haberdasher for HaberdasherService {
     fn make_hat(&self, i: service::PTReq<service::Size>) -> service::PTRes<service::Hat> {
-        Box::new(future::ok(
-            service::Hat { size: i.input.inches, color: "blue".to_string(), name: "fedora".to_string() }.into()
-        ))
+        service::Hat { size: i.input.inches, color: "blue".to_string(), name: "fedora".to_string() }.into_res()
     }
 }
@@ -0,0 +1,263 @@
+extern crate futures;
+extern crate hyper;
+extern crate prost;
+#[macro_use]
+extern crate prost_derive;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate tokio;
+extern crate twirp_rs;
+
+use futures::{future, Future, Stream};
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Client, Request, Server, StatusCode};
+use prost::Message;
+use std::sync::{Arc, Mutex};
+use twirp_rs::{HyperClient, Interceptor, ServerConfig, ServiceRequest, ServiceResponse, TwirpError};
+
+// Not every generated item is exercised
+#[allow(dead_code)]
+mod service {
+    include!(concat!(env!("OUT_DIR"), "/twitch.twirp.example.rs"));
+
+    pub mod wardrobe {
+        include!(concat!(env!("OUT_DIR"), "/twitch.twirp.example.wardrobe.rs"));
+    }
+}
+
+use service::Haberdasher;
+
+struct TestHaberdasher;
+
+impl Haberdasher for TestHaberdasher {
+    fn make_hat(&self, i: service::PTReq<service::Size>) -> service::PTRes<service::Hat> {
+        if i.input.inches <= 0 {
+            return twirp_rs::twirp_err(TwirpError::invalid_argument_field("inches", "must be positive"));
+        }
+        let hat = service::Hat { size: i.input.inches, color: "blue".to_string(), name: "fedora".to_string() };
+        let mut resp = ServiceResponse::new(hat);
+        if let Some(id) = i.headers.get("x-request-id") {
+            resp = resp.with_header("x-request-id", id.clone());
+        }
+        Box::new(future::ok(resp))
+    }
+}
+
+/// Makes hats with `201 Created`, like a service creating resources
+struct CreatingHaberdasher;
+
+impl Haberdasher for CreatingHaberdasher {
+    fn make_hat(&self, i: service::PTReq<service::Size>) -> service::PTRes<service::Hat> {
+        let hat = service::Hat { size: i.input.inches, color: "red".to_string(), name: "beret".to_string() };
+        Box::new(future::result(ServiceResponse::with_status(hat, StatusCode::CREATED)))
+    }
+}
+
+/// Serve `TestHaberdasher` on an ephemeral port for the lifetime of the runtime
+fn start_server(runtime: &mut tokio::runtime::Runtime) -> String {
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(<dyn Haberdasher>::new_server(TestHaberdasher));
+    let root_url = format!("http://{}", server.local_addr());
+    runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+    root_url
+}
+
+#[test]
+fn protobuf_round_trip() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let root_url = start_server(&mut runtime);
+    let client = service::HaberdasherClient::new(HyperClient::new(Client::new(), &root_url));
+
+    let mut req: service::PTReq<service::Size> = service::Size { inches: 12 }.into();
+    req.headers.insert("x-request-id", HeaderValue::from_static("abc"));
+    let resp = runtime.block_on(client.make_hat(req)).unwrap();
+    assert_eq!(resp.output, service::Hat { size: 12, color: "blue".to_string(), name: "fedora".to_string() });
+    assert_eq!(resp.headers[CONTENT_TYPE], "application/protobuf");
+    assert_eq!(resp.headers[CONTENT_LENGTH], resp.output.encoded_len().to_string().as_str());
+    assert_eq!(resp.headers["x-request-id"], "abc");
+}
+
+#[test]
+fn success_status_round_trip() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(<dyn Haberdasher>::new_server(CreatingHaberdasher));
+    let client = service::HaberdasherClient::new(HyperClient::new(Client::new(), &format!("http://{}", server.local_addr())));
+    runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+
+    let resp = runtime.block_on(client.make_hat(service::Size { inches: 8 }.into())).unwrap();
+    assert_eq!(resp.status, StatusCode::CREATED);
+    assert_eq!(resp.output, service::Hat { size: 8, color: "red".to_string(), name: "beret".to_string() });
+}
+
+#[test]
+fn errors_round_trip() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let root_url = start_server(&mut runtime);
+    let client = service::HaberdasherClient::new(HyperClient::new(Client::new(), &root_url));
+
+    let err = runtime.block_on(client.make_hat(service::Size { inches: 0 }.into())).unwrap_err();
+    assert_eq!(err.http_status(), Some(StatusCode::BAD_REQUEST));
+    let err = err.twirp_error().unwrap();
+    assert_eq!(err.code, "invalid_argument");
+    assert_eq!(err.argument(), Some("inches"));
+}
+
+#[test]
+fn trailing_slash_routing() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let status = |runtime: &mut tokio::runtime::Runtime, config| {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(<dyn Haberdasher>::new_server_with_config(TestHaberdasher, config));
+        let uri = format!("http://{}/twirp/twitch.twirp.example.Haberdasher/MakeHat/", server.local_addr());
+        runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+        let body = Body::from(vec![0x08, 0x0c]); // Size { inches: 12 }
+        let req = Request::post(uri).header(CONTENT_TYPE, "application/protobuf").body(body).unwrap();
+        runtime.block_on(Client::new().request(req)).unwrap().status()
+    };
+    assert_eq!(status(&mut runtime, ServerConfig::new()), StatusCode::NOT_FOUND);
+    assert_eq!(status(&mut runtime, ServerConfig::new().with_trailing_slash(true)), StatusCode::OK);
+}
+
+struct RecordMethods(Arc<Mutex<Vec<Option<&'static str>>>>);
+
+impl Interceptor for RecordMethods {
+    fn on_request(&self, req: &ServiceRequest<Vec<u8>>) -> Result<(), TwirpError> {
+        self.0.lock().unwrap().push(req.rpc_method());
+        Ok(())
+    }
+}
+
+#[test]
+fn interceptors_see_rpc_method() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let methods = Arc::new(Mutex::new(Vec::new()));
+    let config = ServerConfig::new().with_interceptor(RecordMethods(methods.clone()));
+    let server = Server::bind(&([127, 0, 0, 1], 0).into())
+        .serve(<dyn Haberdasher>::new_server_with_config(TestHaberdasher, config));
+    let client = service::HaberdasherClient::new(HyperClient::new(Client::new(), &format!("http://{}", server.local_addr())));
+    runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+
+    runtime.block_on(client.make_hat(service::Size { inches: 12 }.into())).unwrap();
+    assert_eq!(*methods.lock().unwrap(), vec![Some("MakeHat")]);
+}
+
+#[test]
+fn routing_errors() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let root_url = start_server(&mut runtime);
+    let mut send = |method: &str, path: &str| {
+        let req = Request::builder().method(method).uri(format!("{}{}", root_url, path))
+            .header(CONTENT_TYPE, "application/protobuf").body(Body::empty()).unwrap();
+        let resp = runtime.block_on(Client::new().request(req)).unwrap();
+        let status = resp.status();
+        let body = runtime.block_on(resp.into_body().concat2()).unwrap();
+        (status, TwirpError::from_json_bytes(status, &body).unwrap())
+    };
+
+    // Known routes only allow POST
+    let (status, err) = send("GET", "/twirp/twitch.twirp.example.Haberdasher/MakeHat");
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(err.code, "bad_route");
+    assert_eq!(err.msg, "Unsupported method GET (only POST is allowed)");
+
+    // Query strings are ignored, so this reaches the service, which rejects the empty size
+    let (status, err) = send("POST", "/twirp/twitch.twirp.example.Haberdasher/MakeHat?trace=1");
+    assert_eq!((status, err.code.as_str()), (StatusCode::BAD_REQUEST, "invalid_argument"));
+
+    // Unknown routes are bad routes too, with a 404 as the spec requires
+    for path in &["/twirp/twitch.twirp.example.Haberdasher/MakeScarf", "/twirp/twitch.twirp.example.Closet/MakeHat"] {
+        let (status, err) = send("POST", path);
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(err.code, "bad_route");
+    }
+}
+
+#[test]
+fn json_round_trip() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let root_url = start_server(&mut runtime);
+
+    let req = Request::post(format!("{}/twirp/twitch.twirp.example.Haberdasher/MakeHat", root_url))
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(r#"{"inches":3}"#))
+        .unwrap();
+    let resp = runtime.block_on(Client::new().request(req)).unwrap();
+    assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+    let body = runtime.block_on(resp.into_body().concat2()).unwrap();
+    assert_eq!(&body[..], &br#"{"size":3,"color":"blue","name":"fedora"}"#[..]);
+}
+
+/// JSON behind a 4-byte big-endian length, standing in for an internal wire format
+struct PrefixedJson;
+
+impl twirp_rs::Codec for PrefixedJson {
+    fn media_type(&self) -> &str {
+        "application/x-prefixed-json"
+    }
+
+    fn encode(&self, value: &serde_json::Value) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let json = serde_json::to_vec(value)?;
+        let mut body = (json.len() as u32).to_be_bytes().to_vec();
+        body.extend(json);
+        Ok(body)
+    }
+
+    fn decode(&self, body: &[u8]) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+        if body.len() < 4 || u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize != body.len() - 4 {
+            return Err("bad length prefix".into());
+        }
+        Ok(serde_json::from_slice(&body[4..])?)
+    }
+}
+
+#[test]
+fn codec_round_trip() {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let config = ServerConfig::new().with_codec(PrefixedJson);
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(<dyn Haberdasher>::new_server_with_config(TestHaberdasher, config));
+    let client = HyperClient::new(Client::new(), &format!("http://{}", server.local_addr()));
+    runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+
+    let path = "/twirp/twitch.twirp.example.Haberdasher/MakeHat";
+    let go = |req: ServiceRequest<service::Size>| client.go_codec::<_, service::Hat>(path, req, Arc::new(PrefixedJson));
+    let resp = runtime.block_on(go(service::Size { inches: 5 }.into())).unwrap();
+    assert_eq!(resp.headers[CONTENT_TYPE], "application/x-prefixed-json");
+    assert_eq!(resp.output, service::Hat { size: 5, color: "blue".to_string(), name: "fedora".to_string() });
+
+    // Service errors are still JSON Twirp errors
+    let err = runtime.block_on(go(service::Size { inches: 0 }.into())).unwrap_err();
+    assert_eq!(err.twirp_code(), Some("invalid_argument"));
+
+    let mut req = ServiceRequest::new(b"{}".to_vec());
+    req.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-prefixed-json"));
+    let resp = runtime.block_on(client.go_raw(path, req)).unwrap();
+    assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+    assert_eq!(resp.to_proto::<service::Hat>().unwrap_err().twirp_code(), Some("malformed"));
+}
+
+struct TestTailor;
+
+impl service::wardrobe::Tailor for TestTailor {
+    fn hem(&self, i: service::wardrobe::PTReq<service::wardrobe::Outfit>) -> service::wardrobe::PTRes<service::wardrobe::Outfit> {
+        let mut outfit = i.input;
+        outfit.scarf.truncate(4);
+        twirp_rs::twirp_ok(outfit)
+    }
+}
+
+#[test]
+fn services_of_one_package() {
+    use service::wardrobe::{Tailor, Wardrobe};
+
+    assert_eq!(<dyn Wardrobe>::rpc_paths(), &[("Dress", "/twirp/twitch.twirp.example.wardrobe.Wardrobe/Dress")]);
+    assert_eq!(<dyn Tailor>::rpc_paths(), &[("Hem", "/twirp/twitch.twirp.example.wardrobe.Tailor/Hem")]);
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(<dyn Tailor>::new_server(TestTailor));
+    let client = service::wardrobe::TailorClient::new(HyperClient::new(Client::new(), &format!("http://{}", server.local_addr())));
+    runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+
+    let outfit = service::wardrobe::Outfit { hat: None, scarf: "wool scarf".to_string() };
+    let resp = runtime.block_on(client.hem(outfit.into())).unwrap();
+    assert_eq!(resp.output.scarf, "wool");
+}
@@ -0,0 +1,247 @@
+//! A Twirp client on hyper 0.14 and `std::future`, for projects without the futures 0.1 stack
+//!
+//! `HyperClient` here parallels the crate's `HyperClient` with `async fn` methods. Its requests and
+//! responses have their own types, with the `http` 0.2 headers and statuses of hyper 0.14, while
+//! errors are the same `ProstTwirpError`s; transport failures are `ProstTwirpError::TransportError`.
+//! Requests have to run within a tokio 1 runtime, as hyper 0.14 needs one.
+
+use hyper014::body::HttpBody;
+use hyper014::client::connect::{Connect, HttpConnector};
+use hyper014::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, USER_AGENT};
+use hyper014::{Body, Client, Method, Request, StatusCode, Uri, Version};
+use prost::Message;
+use std::time::Duration;
+use crate::service_run::{self, ProstTwirpError, TwirpError, DEFAULT_USER_AGENT, TIMEOUT_HEADER};
+
+/// The result of every request of the client
+pub type PTResult<O> = Result<ServiceResponse<O>, ProstTwirpError>;
+
+/// A request with its headers and input, as sent by the client
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceRequest<T> {
+    /// The request headers
+    pub headers: HeaderMap<HeaderValue>,
+    /// The request input
+    pub input: T,
+}
+
+impl<T> ServiceRequest<T> {
+    /// Create a request for the given input, without headers
+    pub fn new(input: T) -> ServiceRequest<T> {
+        ServiceRequest { headers: HeaderMap::new(), input }
+    }
+
+    /// Add the given header to the request, replacing any with the same name
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> ServiceRequest<T> {
+        self.headers.insert(name, value);
+        self
+    }
+}
+
+impl<T: Message + Default + 'static> From<T> for ServiceRequest<T> {
+    fn from(v: T) -> ServiceRequest<T> { ServiceRequest::new(v) }
+}
+
+/// A response with its HTTP info and output, as received by the client
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceResponse<T> {
+    /// The HTTP version of the response
+    pub version: Version,
+    /// The response headers
+    pub headers: HeaderMap<HeaderValue>,
+    /// The response status, `2xx` for every response but those of `go_raw`
+    pub status: StatusCode,
+    /// The response output
+    pub output: T,
+}
+
+impl ServiceResponse<Vec<u8>> {
+    /// Decode the byte-array response like the crate's `ServiceResponse::to_proto`
+    ///
+    /// Error statuses are decoded into their Twirp errors.
+    pub fn to_proto<T: Message + Default + 'static>(&self) -> PTResult<T> {
+        let mut headers = hyper::HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (hyper::header::HeaderName::from_bytes(name.as_str().as_bytes()),
+                                            hyper::header::HeaderValue::from_bytes(value.as_bytes())) {
+                headers.append(name, value);
+            }
+        }
+        let version = match self.version {
+            Version::HTTP_09 => hyper::Version::HTTP_09,
+            Version::HTTP_10 => hyper::Version::HTTP_10,
+            Version::HTTP_2 => hyper::Version::HTTP_2,
+            _ => hyper::Version::HTTP_11,
+        };
+        let status = hyper::StatusCode::from_u16(self.status.as_u16()).unwrap_or(hyper::StatusCode::INTERNAL_SERVER_ERROR);
+        let raw = service_run::ServiceResponse { version, headers, status, output: self.output.clone() };
+        let resp = raw.to_proto::<T>()?;
+        Ok(ServiceResponse { version: self.version, headers: self.headers.clone(), status: self.status, output: resp.output })
+    }
+}
+
+/// A Twirp client wrapping a hyper 0.14 client
+///
+/// The client is generic over its connector, so e.g. a TLS connector can be used for HTTPS.
+#[derive(Debug, Clone)]
+pub struct HyperClient<C = HttpConnector> {
+    client: Client<C, Body>,
+    root_url: String,
+    timeout: Option<Duration>,
+    max_response_size: Option<usize>,
+}
+
+impl<C> HyperClient<C> where C: Connect + Clone + Send + Sync + 'static {
+    /// Create a new client wrapper for the given client and root using protobuf
+    pub fn new(client: Client<C, Body>, root_url: &str) -> HyperClient<C> {
+        HyperClient { client, root_url: root_url.trim_end_matches('/').to_string(), timeout: None, max_response_size: None }
+    }
+
+    /// Fail requests that don't complete within the given duration with a `Timeout` error
+    ///
+    /// The timeout is also sent in `TIMEOUT_HEADER`, like the crate's `HyperClient` does by default.
+    pub fn with_timeout(self, timeout: Duration) -> HyperClient<C> {
+        HyperClient { timeout: Some(timeout), ..self }
+    }
+
+    /// Fail responses with bodies larger than the given size in bytes with a `resource_exhausted` error
+    pub fn with_max_response_size(self, max_response_size: usize) -> HyperClient<C> {
+        HyperClient { max_response_size: Some(max_response_size), ..self }
+    }
+
+    /// The full URL of the given path under the root URL
+    pub fn url(&self, path: &str) -> String {
+        let (base, query) = match self.root_url.find('?') {
+            Some(i) => self.root_url.split_at(i),
+            None => (self.root_url.as_str(), ""),
+        };
+        format!("{}/{}{}", base.trim_end_matches('/'), path.trim_start_matches('/'), query)
+    }
+
+    /// Invoke the given protobuf request for the given path, and decode its response
+    pub async fn go<I, O>(&self, path: &str, req: ServiceRequest<I>) -> PTResult<O>
+            where I: Message, O: Message + Default + 'static {
+        let mut input = Vec::new();
+        req.input.encode(&mut input).map_err(ProstTwirpError::ProstEncodeError)?;
+        let mut headers = req.headers;
+        headers.entry(CONTENT_TYPE).or_insert_with(|| HeaderValue::from_static("application/protobuf"));
+        self.go_raw(path, ServiceRequest { headers, input }).await?.to_proto()
+    }
+
+    /// Post the given bytes to the given path as they are, and return the raw response
+    ///
+    /// Like the crate's `HyperClient::go_raw`, the response is returned whatever its status, and
+    /// `to_proto` turns error statuses into errors.
+    pub async fn go_raw(&self, path: &str, req: ServiceRequest<Vec<u8>>) -> PTResult<Vec<u8>> {
+        let url = self.url(path);
+        let uri: Uri = url.parse().map_err(|_| ProstTwirpError::InvalidUri(url.clone()))?;
+        let mut hyper_req = Request::new(Body::from(req.input));
+        *hyper_req.method_mut() = Method::POST;
+        *hyper_req.uri_mut() = uri;
+        *hyper_req.headers_mut() = req.headers;
+        hyper_req.headers_mut().entry(USER_AGENT).or_insert_with(|| HeaderValue::from_static(DEFAULT_USER_AGENT));
+        if let Some(timeout) = self.timeout {
+            hyper_req.headers_mut().insert(TIMEOUT_HEADER, HeaderValue::from(timeout.as_millis() as u64));
+        }
+
+        match self.timeout {
+            Some(timeout) => tokio1::time::timeout(timeout, self.send(hyper_req)).await
+                .unwrap_or(Err(ProstTwirpError::Timeout)),
+            None => self.send(hyper_req).await,
+        }
+    }
+
+    async fn send(&self, req: Request<Body>) -> PTResult<Vec<u8>> {
+        let transport = |err: hyper014::Error| ProstTwirpError::TransportError(Box::new(err));
+        let resp = self.client.request(req).await.map_err(transport)?;
+        let (version, headers, status) = (resp.version(), resp.headers().clone(), resp.status());
+        let mut body = resp.into_body();
+        let mut output = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(transport)?;
+            if self.max_response_size.is_some_and(|max| output.len() + chunk.len() > max) {
+                return Err(TwirpError::resource_exhausted("Response body too large").into());
+            }
+            output.extend_from_slice(&chunk);
+        }
+        Ok(ServiceResponse { version, headers, status, output })
+    }
+}
+
+#[cfg(test)]
+mod async_client_tests {
+    use super::*;
+    use futures::Future;
+    use prost_derive::Message;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Size {
+        #[prost(int32, tag = "1")]
+        inches: i32,
+    }
+
+    /// Serve doubled sizes with hyper 0.12 on a tokio 0.1 runtime, rejecting non-positive ones
+    fn start_server(runtime: &mut tokio::runtime::Runtime) -> String {
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(|| hyper::service::service_fn(|req: hyper::Request<hyper::Body>| {
+                service_run::ServiceRequest::<Size>::from_hyper_proto(req).then(|req| Ok::<_, hyper::Error>(match req {
+                    Ok(ref req) if req.input.inches > 0 =>
+                        service_run::ServiceResponse::new(Size { inches: req.input.inches * 2 }).to_hyper_proto().unwrap(),
+                    _ => TwirpError::invalid_argument("Size must be positive").to_hyper_resp().unwrap(),
+                }))
+            }));
+        let root_url = format!("http://{}", server.local_addr());
+        runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+        root_url
+    }
+
+    #[test]
+    fn go() {
+        let mut server_runtime = tokio::runtime::Runtime::new().unwrap();
+        let root_url = start_server(&mut server_runtime);
+        let runtime = tokio1::runtime::Runtime::new().unwrap();
+        let client = HyperClient::new(Client::new(), &root_url);
+
+        let resp: ServiceResponse<Size> = runtime.block_on(client.go("/twirp/pkg.Svc/Method", Size { inches: 6 }.into())).unwrap();
+        assert_eq!((resp.status, resp.output), (StatusCode::OK, Size { inches: 12 }));
+        assert_eq!(resp.headers[CONTENT_TYPE], "application/protobuf");
+
+        let err = runtime.block_on(client.go::<_, Size>("/twirp/pkg.Svc/Method", Size { inches: 0 }.into())).unwrap_err();
+        assert_eq!(err.twirp_code(), Some("invalid_argument"));
+        assert_eq!(err.http_status(), Some(hyper::StatusCode::BAD_REQUEST));
+
+        let resp = runtime.block_on(client.go_raw("/twirp/pkg.Svc/Method", ServiceRequest::new(vec![]))).unwrap();
+        assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+
+        let client = client.with_max_response_size(1);
+        let err = runtime.block_on(client.go::<_, Size>("/twirp/pkg.Svc/Method", Size { inches: 6 }.into())).unwrap_err();
+        assert_eq!(err.twirp_code(), Some("resource_exhausted"));
+    }
+
+    #[test]
+    fn transport_errors() {
+        let runtime = tokio1::runtime::Runtime::new().unwrap();
+        let client = HyperClient::new(Client::new(), "http://local host");
+        match runtime.block_on(client.go::<_, Size>("/twirp/pkg.Svc/Method", Size { inches: 6 }.into())) {
+            Err(ProstTwirpError::InvalidUri(uri)) => assert_eq!(uri, "http://local host/twirp/pkg.Svc/Method"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // Nothing listens on a port of a dropped listener
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let root_url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let err = runtime.block_on(HyperClient::new(Client::new(), &root_url).go::<_, Size>("/twirp/pkg.Svc/Method", Size { inches: 6 }.into()))
+            .unwrap_err();
+        assert_eq!(service_run::TwirpCode::classify(&err), service_run::TwirpCode::Unavailable);
+
+        // Accept connections but never reply to them
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = HyperClient::new(Client::new(), &format!("http://{}", listener.local_addr().unwrap()))
+            .with_timeout(Duration::from_millis(50));
+        match runtime.block_on(client.go::<_, Size>("/twirp/pkg.Svc/Method", Size { inches: 6 }.into())) {
+            Err(ProstTwirpError::Timeout) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -1,4 +1,5 @@
-#![recursion_limit="256"]
+#![recursion_limit="512"]
+#![allow(clippy::result_large_err)]
 
 #[cfg(feature = "service-gen")]
 mod service_gen;
@@ -8,3 +9,18 @@ pub use self::service_gen::TwirpServiceGenerator;
 
 mod service_run;
 pub use self::service_run::*;
+
+#[cfg(feature = "async")]
+mod service_async;
+
+#[cfg(feature = "async")]
+pub use self::service_async::*;
+
+#[cfg(feature = "async-client")]
+pub mod async_client;
+
+#[cfg(feature = "test-util")]
+mod service_mock;
+
+#[cfg(feature = "test-util")]
+pub use self::service_mock::*;
@@ -0,0 +1,55 @@
+use futures03::compat::{Compat, Compat01As03};
+use hyper::client::connect::Connect;
+use prost::Message;
+use std::future::Future;
+use crate::service_run::{HyperClient, PTRes, ProstTwirpError, ServiceRequest, ServiceResponse};
+
+pub use async_trait::async_trait;
+
+/// The result of every async service method
+pub type PTResult<O> = Result<ServiceResponse<O>, ProstTwirpError>;
+
+/// Turn an async service response into a boxed future, as used by generated server handlers
+pub fn from_async<O, F>(fut: F) -> PTRes<O>
+        where F: Future<Output=PTResult<O>> + Send + 'static {
+    Box::new(Compat::new(Box::pin(fut)))
+}
+
+/// Turn a boxed future service response into an async one, as used by generated clients
+///
+/// The boxed future still has to run within a tokio runtime, since hyper needs one.
+pub async fn into_async<O>(res: PTRes<O>) -> PTResult<O> {
+    Compat01As03::new(res).await
+}
+
+impl<C> HyperClient<C> where C: Connect + 'static, C::Future: 'static {
+    /// Invoke the given request like `go`, as a `std::future::Future`
+    ///
+    /// Like `into_async`, the request still has to run within a tokio 0.1 runtime for hyper 0.12; the
+    /// `async_client` module, behind the `async-client` feature, has a client on hyper 0.14 instead.
+    pub async fn go_async<I, O>(&self, path: &str, req: ServiceRequest<I>) -> PTResult<O>
+            where I: Message + Default + Clone + 'static, O: Message + Default + 'static {
+        into_async(self.go(path, req)).await
+    }
+
+    /// Post the given bytes like `go_raw`, as a `std::future::Future`
+    pub async fn go_raw_async(&self, path: &str, req: ServiceRequest<Vec<u8>>) -> PTResult<Vec<u8>> {
+        into_async(self.go_raw(path, req)).await
+    }
+}
+
+#[cfg(test)]
+mod async_client_tests {
+    use super::*;
+    use hyper::Client;
+
+    #[test]
+    fn go_async() {
+        let client = HyperClient::new(Client::new(), "http://local host");
+        let res: PTResult<()> = futures03::executor::block_on(client.go_async("/twirp/pkg.Svc/Method", ServiceRequest::new(())));
+        match res {
+            Err(ProstTwirpError::InvalidUri(uri)) => assert_eq!(uri, "http://local host/twirp/pkg.Svc/Method"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
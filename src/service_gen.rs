@@ -1,22 +1,109 @@
-use prost_build::{Method, Service, ServiceGenerator};
+use prost_build::{Comments, Method, Service, ServiceGenerator};
 use proc_macro2::{TokenStream, Ident, Span, Literal};
+use std::collections::HashSet;
 use std::fmt::Write;
 use std::process::{Command, Stdio};
 use quote::quote;
 
-#[derive(Default)]
+/// A prost-build service generator for Twirp clients and servers
+///
+/// `TwirpServiceGenerator::new()` generates servers only, `TwirpServiceGenerator::both()` generates
+/// clients and servers, and `TwirpServiceGenerator::default()` generates neither, only the service
+/// traits, until `with_client` or `with_server` turn them on.
 pub struct TwirpServiceGenerator {
+    /// Generate a `{Service}Client` implementing the service trait
     pub generate_client: bool,
+    /// Generate the `server_handler` functions dispatching requests to a service implementation
     pub generate_server: bool,
+    /// Accept `application/json` requests in the server handler, and reply to them in JSON
+    ///
+    /// Responses follow the `Accept` header of the request if it asks for the other encoding.
+    ///
+    /// This requires every message to implement `serde::Serialize` and `serde::Deserialize`,
+    /// e.g. by adding a `type_attribute` to the `prost_build::Config`. The JSON follows those
+    /// derives rather than the canonical protobuf JSON mapping, so field name casing, enums and
+    /// well-known types are up to the serde attributes.
+    pub generate_json: bool,
+    /// Only accept `application/json` requests in the server handler, and always reply in JSON
+    ///
+    /// Protobuf requests are rejected with a `415` `bad_content_type` error, and so are those of
+    /// any `ServerConfig` codecs. This needs the same serde derives as `generate_json`, which it
+    /// implies.
+    pub json_only: bool,
+    /// The path prefix of every route, `/twirp` by default
+    ///
+    /// An empty prefix serves routes directly under the root, e.g. `/package.Service/Method`.
+    pub prefix: String,
+    /// Generate `async fn` service methods using `async_trait`
+    ///
+    /// This requires the `async` feature of this crate, and a 2018 edition crate for the generated code.
+    pub generate_async: bool,
+    /// Format the generated code with `rustfmt`, true by default
+    ///
+    /// Disabling this skips spawning `rustfmt` entirely, for faster and deterministic builds.
+    pub format: bool,
+    /// The path of this crate in the generated code, `twirp_rs` by default
+    ///
+    /// It is resolved from the crate root, so it can be a renamed dependency or a re-export,
+    /// e.g. `twirp` or `my_facade::twirp`.
+    pub crate_path: String,
+    /// Generate a `classify` function per service, categorizing client errors by canonical code
+    pub generate_classify: bool,
+    /// Use `prost_types` for well-known types like `google.protobuf.Empty`, true by default
+    ///
+    /// This matches `prost_build::Config`, so it should be disabled along with
+    /// `compile_well_known_types`.
+    pub prost_types: bool,
+    /// Put the generated items of every service in a module named after it, e.g. `haberdasher`
+    ///
+    /// This keeps items like the `PTReq` and `PTRes` aliases from colliding when several services
+    /// are generated into the same module. Message types are referred to from the parent module.
+    pub service_modules: bool,
+    /// The packages whose type aliases were already generated
+    ///
+    /// prost-build generates every service of a package into the same module.
+    aliased_packages: HashSet<String>,
 }
 
-impl TwirpServiceGenerator {
-    pub fn new() -> Self {
+impl Default for TwirpServiceGenerator {
+    fn default() -> Self {
         TwirpServiceGenerator {
             generate_client: false,
-            generate_server: true
+            generate_server: false,
+            generate_json: false,
+            json_only: false,
+            prefix: "/twirp".to_string(),
+            generate_async: false,
+            format: true,
+            crate_path: "twirp_rs".to_string(),
+            generate_classify: false,
+            prost_types: true,
+            service_modules: false,
+            aliased_packages: HashSet::new(),
         }
     }
+}
+
+impl TwirpServiceGenerator {
+    /// Create a generator for servers only
+    pub fn new() -> Self {
+        TwirpServiceGenerator::default().with_server(true)
+    }
+
+    /// Create a generator for both clients and servers
+    pub fn both() -> Self {
+        TwirpServiceGenerator::default().with_client(true).with_server(true)
+    }
+
+    /// Set whether clients are generated
+    pub fn with_client(self, generate_client: bool) -> Self {
+        TwirpServiceGenerator { generate_client, ..self }
+    }
+
+    /// Set whether servers are generated
+    pub fn with_server(self, generate_server: bool) -> Self {
+        TwirpServiceGenerator { generate_server, ..self }
+    }
 
     #[allow(dead_code)]
     fn comment(&self, comment: &str) -> TokenStream {
@@ -32,43 +119,177 @@ impl TwirpServiceGenerator {
         self.ident(&service.name)
     }
 
+    /// The fully-qualified proto name of the service, e.g. `package.Service`
+    fn service_full_name(&self, service: &Service) -> String {
+        // Services without a package are routed by their name alone
+        if service.package.is_empty() {
+            service.proto_name.clone()
+        } else {
+            format!("{}.{}", service.package, service.proto_name)
+        }
+    }
+
+    /// The path prefix of every route of the service, e.g. `/twirp/package.Service/`
+    fn twirp_service_path(&self, service: &Service) -> String {
+        format!("{}/{}/", self.prefix.trim_end_matches('/'), self.service_full_name(service))
+    }
+
     fn twirp_uri(&self, service: &Service, method: &Method) -> Literal {
-        Literal::string(&format!("/twirp/{}.{}/{}", service.package, service.proto_name, method.proto_name))
+        Literal::string(&format!("{}{}", self.twirp_service_path(service), method.proto_name))
+    }
+
+    /// The name of the module of the service's items with `service_modules`, e.g. `haberdasher`
+    fn service_module_name(&self, service: &Service) -> Ident {
+        let chars: Vec<char> = service.name.chars().collect();
+        let mut name = String::new();
+        for (i, &c) in chars.iter().enumerate() {
+            // Words start at a capital after a lowercase letter or digit, or before one, e.g. `HTTPServer`
+            let starts_word = i > 0 && c.is_uppercase() && (!chars[i - 1].is_uppercase()
+                || chars.get(i + 1).is_some_and(|next| next.is_lowercase()));
+            if starts_word && chars[i - 1] != '_' {
+                name.push('_');
+            }
+            name.extend(c.to_lowercase());
+        }
+        self.ident(&name)
     }
 
     fn twirp_mod(&self) -> TokenStream {
-        let modname = Ident::new("twirp_rs", Span::call_site());
-        quote!{ ::#modname }
+        let segments = self.crate_path.trim_start_matches("::").split("::").map(|s| self.ident(s));
+        quote!{ #( ::#segments )* }
     }
 
     fn generate_type_aliases(&self) -> TokenStream {
         let module = self.twirp_mod();
 
+        let async_aliases = if self.generate_async {
+            quote! { pub type PTResult<O> = #module::PTResult<O>; }
+        } else {
+            TokenStream::new()
+        };
+
         quote! {
             pub type PTReq<I> = #module::PTReq<I>;
             pub type PTRes<O> = #module::PTRes<O>;
+            #async_aliases
         }
     }
 
+    /// The Rust type of a method input or output, as prost-build generates it for message fields
+    ///
+    /// prost-build resolves method types relative to the current module even for well-known types,
+    /// which only exist there with `compile_well_known_types`.
+    ///
+    /// Types in other packages are paths like `super::package::Message`, which are parsed as a
+    /// whole rather than as a single identifier.
+    fn message_type(&self, rust_type: &str, proto_type: &str) -> TokenStream {
+        let well_known = if self.prost_types {
+            proto_type.trim_start_matches('.').strip_prefix("google.protobuf.")
+        } else {
+            None
+        };
+        let rust_type = match well_known {
+            Some("Empty") => "()".to_string(),
+            Some("BoolValue") => "bool".to_string(),
+            Some("BytesValue") => "::std::vec::Vec<u8>".to_string(),
+            Some("DoubleValue") => "f64".to_string(),
+            Some("FloatValue") => "f32".to_string(),
+            Some("Int32Value") => "i32".to_string(),
+            Some("Int64Value") => "i64".to_string(),
+            Some("StringValue") => "::std::string::String".to_string(),
+            Some("UInt32Value") => "u32".to_string(),
+            Some("UInt64Value") => "u64".to_string(),
+            Some(name) => format!("::prost_types::{}", name),
+            // Relative paths are one module further away from within a service module
+            None if self.service_modules && !rust_type.starts_with("::") => format!("super::{}", rust_type),
+            None => rust_type.to_string(),
+        };
+        let rust_type: syn::Type = syn::parse_str(&rust_type)
+            .unwrap_or_else(|err| panic!("Invalid type {} for {}: {}", rust_type, proto_type, err));
+        quote! { #rust_type }
+    }
+
     fn method_sig(&self, method: &Method) -> TokenStream {
         let name = self.ident(&method.name);
         let module = self.twirp_mod();
-        let input_type = self.ident(&method.input_type);
-        let output_type = self.ident(&method.output_type);
+        let input_type = self.message_type(&method.input_type, &method.input_proto_type);
+        let output_type = self.message_type(&method.output_type, &method.output_proto_type);
 
-        quote! {
-            fn #name(&self, i: #module::PTReq<#input_type>) -> #module::PTRes<#output_type>
+        if self.generate_async {
+            quote! {
+                async fn #name(&self, i: #module::PTReq<#input_type>) -> #module::PTResult<#output_type>
+            }
+        } else {
+            quote! {
+                fn #name(&self, i: #module::PTReq<#input_type>) -> #module::PTRes<#output_type>
+            }
+        }
+    }
+
+    /// The proto comments as doc attributes, one per line
+    fn doc_attrs(&self, comments: &Comments) -> TokenStream {
+        let lines = comments.leading.iter().chain(&comments.trailing);
+        quote! { #( #[doc = #lines] )* }
+    }
+
+    fn async_attr(&self) -> TokenStream {
+        let module = self.twirp_mod();
+        if self.generate_async {
+            quote! { #[#module::async_trait] }
+        } else {
+            TokenStream::new()
         }
     }
 
     fn generate_main_trait(&self, service: &Service) -> TokenStream {
         let name = self.service_name(service);
-        let methods = service.methods.iter().map(|method| self.method_sig(method));
+        let methods = service.methods.iter().map(|method| {
+            let docs = self.doc_attrs(&method.comments);
+            let signature = self.method_sig(method);
+            quote! { #docs #signature }
+        });
+        let docs = self.doc_attrs(&service.comments);
+        let async_attr = self.async_attr();
+        let rpc_paths = service.methods.iter().map(|method| {
+            let name = Literal::string(&method.proto_name);
+            let uri = self.twirp_uri(service, method);
+            quote! { (#name, #uri) }
+        });
+
+        // Shared services forward to the service they point to, so state is shared across requests
+        let shared_methods = service.methods.iter().map(|method| {
+            let signature = self.method_sig(method);
+            let method_name = self.ident(&method.name);
+            let await_suffix = if self.generate_async { quote! { .await } } else { TokenStream::new() };
+            quote! {
+                #signature {
+                    (**self).#method_name(i) #await_suffix
+                }
+            }
+        });
 
         quote! {
-            pub trait #name: Send {
+            #docs
+            #async_attr
+            // Services are shared across the threads of a hyper runtime, and async methods borrow
+            // them across await points
+            pub trait #name: Send + Sync {
                 #( #methods; )*
             }
+
+            #async_attr
+            impl<T: #name + ?Sized> #name for ::std::sync::Arc<T> {
+                #( #shared_methods )*
+            }
+
+            impl dyn #name {
+                /// The name and Twirp path of every method of the service, in proto order
+                ///
+                /// This is e.g. for registering the routes in a proxy, or documenting them.
+                pub fn rpc_paths() -> &'static [(&'static str, &'static str)] {
+                    &[#( #rpc_paths ),*]
+                }
+            }
         }
     }
 
@@ -77,73 +298,397 @@ impl TwirpServiceGenerator {
         let name = self.service_name(service);
         let client_name = self.ident(&format!("{}Client", service.name));
 
+        let async_attr = self.async_attr();
+        let docs = self.doc_attrs(&service.comments);
+
         let methods = service.methods.iter().map(|method| {
             let signature = self.method_sig(method);
             let uri = self.twirp_uri(service, method);
+            let call = if self.generate_async {
+                quote! { #module::into_async(self.0.go(#uri, i)).await }
+            } else {
+                quote! { self.0.go(#uri, i) }
+            };
 
             quote! {
                 #signature {
-                    self.0.go(#uri, i)
+                    #call
                 }
             }
         });
 
         quote! {
-            pub struct #client_name(pub #module::HyperClient);
+            #docs
+            #[derive(Clone)]
+            pub struct #client_name<C = #module::HyperClient>(pub C);
+
+            impl<C> #client_name<C> {
+                /// Wrap the given client, e.g. a `HyperClient`
+                pub fn new(client: C) -> Self {
+                    #client_name(client)
+                }
+
+                /// The underlying client, e.g. to inspect its transport settings
+                pub fn inner(&self) -> &C {
+                    &self.0
+                }
 
-            impl #name {
-                pub fn client(client: ::hyper::Client<::hyper::client::HttpConnector, ::hyper::Body>, root_url: &str) -> Box<#name> {
+                /// The underlying client, e.g. to change its timeout
+                pub fn inner_mut(&mut self) -> &mut C {
+                    &mut self.0
+                }
+
+                /// Unwrap the underlying client
+                pub fn into_inner(self) -> C {
+                    self.0
+                }
+            }
+
+            impl dyn #name {
+                /// Create a concrete client for the given `HyperClient`, which can still be configured
+                pub fn new_client<C>(hyper_client: #module::HyperClient<C>) -> #client_name<#module::HyperClient<C>>
+                    where C: ::hyper::client::connect::Connect + 'static, C::Future: 'static
+                {
+                    #client_name(hyper_client)
+                }
+
+                pub fn client(client: ::hyper::Client<::hyper::client::HttpConnector, ::hyper::Body>, root_url: &str) -> Box<dyn #name> {
+                    Box::new(#client_name(#module::HyperClient::new(client, root_url)))
+                }
+
+                pub fn client_with_connector<C>(client: ::hyper::Client<C, ::hyper::Body>, root_url: &str) -> Box<dyn #name>
+                    where C: ::hyper::client::connect::Connect + 'static, C::Future: 'static
+                {
                     Box::new(#client_name(#module::HyperClient::new(client, root_url)))
                 }
+
+                /// Create a client that can be cloned cheaply and shared across tasks
+                pub fn shared_client(client: ::hyper::Client<::hyper::client::HttpConnector, ::hyper::Body>, root_url: &str)
+                    -> ::std::sync::Arc<dyn #name>
+                {
+                    ::std::sync::Arc::new(#client_name(#module::HyperClient::new(client, root_url)))
+                }
             }
 
-            impl #name for #client_name {
+            #async_attr
+            impl<C: #module::TwirpClient> #name for #client_name<C> {
                 #( #methods )*
             }
         }
     }
 
-    fn generate_http_handler(&self, service: &Service) -> TokenStream {
+    fn generate_classify(&self, service: &Service) -> TokenStream {
         let name = self.service_name(service);
         let module = self.twirp_mod();
 
-        let handlers = service.methods.iter().map(|method| {
+        quote! {
+            impl dyn #name {
+                /// The canonical Twirp code of an error returned by this service
+                pub fn classify(err: &#module::ProstTwirpError) -> #module::TwirpCode {
+                    #module::TwirpCode::classify(err)
+                }
+            }
+        }
+    }
+
+    fn method_enum_name(&self, service: &Service) -> Ident {
+        self.ident(&format!("{}Method", service.name))
+    }
+
+    fn generate_method_enum(&self, service: &Service) -> TokenStream {
+        let method_enum = self.method_enum_name(service);
+        let service_name = &service.name;
+        let variants: Vec<_> = service.methods.iter().map(|method| self.ident(&method.proto_name)).collect();
+        let variant_decls = &variants;
+        let all = variants.iter().map(|variant| quote! { #method_enum::#variant });
+        let from_path = service.methods.iter().zip(&variants).map(|(method, variant)| {
             let uri = self.twirp_uri(service, method);
+            quote! { #uri => Some(#method_enum::#variant) }
+        });
+        let path = service.methods.iter().zip(&variants).map(|(method, variant)| {
+            let uri = self.twirp_uri(service, method);
+            quote! { #method_enum::#variant => #uri }
+        });
+        let name = service.methods.iter().zip(&variants).map(|(method, variant)| {
+            let name = Literal::string(&method.proto_name);
+            quote! { #method_enum::#variant => #name }
+        });
+        let doc = format!("A method of the `{}` service", service_name);
+
+        quote! {
+            #[doc = #doc]
+            #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+            pub enum #method_enum {
+                #( #variant_decls, )*
+            }
+
+            impl #method_enum {
+                /// Every method of the service, in proto order
+                pub const ALL: &'static [#method_enum] = &[#( #all ),*];
+
+                /// The method routed at the given path, if any
+                pub fn from_path(path: &str) -> Option<#method_enum> {
+                    match path {
+                        #( #from_path, )*
+                        _ => None,
+                    }
+                }
+
+                /// The path the method is routed at
+                pub fn path(self) -> &'static str {
+                    match self {
+                        #( #path, )*
+                    }
+                }
+
+                /// The method name as written in the proto file
+                pub fn name(self) -> &'static str {
+                    match self {
+                        #( #name, )*
+                    }
+                }
+            }
+        }
+    }
+
+    fn generate_http_handler(&self, service: &Service) -> TokenStream {
+        let name = self.service_name(service);
+        let module = self.twirp_mod();
+
+        let (decode, encode) = if self.json_only {
+            (quote! { req.to_proto_json() }, quote! { v.to_json_raw() })
+        } else if self.generate_json {
+            (quote! { match encoding { Encoding::Json => req.to_proto_json(), Encoding::Protobuf => req.to_proto() } },
+             quote! { match response_encoding { Encoding::Json => v.to_json_raw(), Encoding::Protobuf => v.to_proto_raw() } })
+        } else {
+            (quote! { req.to_proto() }, quote! { v.to_proto_raw() })
+        };
+
+        let method_enum = self.method_enum_name(service);
+        let service_path = self.twirp_service_path(service);
+        let service_full_name = self.service_full_name(service);
+
+        let calls: Vec<_> = service.methods.iter().map(|method| {
+            let variant = self.ident(&method.proto_name);
             let method = self.ident(&method.name);
+            let call = if self.generate_async {
+                quote! { #module::from_async(async move { service.#method(v).await }) }
+            } else {
+                quote! { service.#method(v) }
+            };
+            (variant, call)
+        }).collect();
+
+        let handlers = calls.iter().map(|(variant, call)| quote! {
+            #method_enum::#variant => {
+                Box::new(future::result(#decode).and_then(move |v| #call).and_then(move |v| #encode))
+            }
+        });
 
-            quote! {
-                (Method::POST, #uri) => { Box::new(future::result(req.to_proto()).and_then(move |v| service.#method(v)).and_then(|v| v.to_hyper_proto())) }
+        let codec_handlers = calls.iter().map(|(variant, call)| quote! {
+            #method_enum::#variant => {
+                let response_codec = codec.clone();
+                Box::new(future::result(req.to_proto_codec(&*codec)).and_then(move |v| #call)
+                    .and_then(move |v| v.to_codec_raw(&*response_codec)))
             }
         });
 
-        quote! {
-            impl #name {
-                pub fn server_handler<T: 'static + #name>(service: T, req: ::hyper::Request<::hyper::Body>) ->
-                    Box<::futures::Future<Item = ::hyper::Response<::hyper::Body>, Error = ::hyper::Error> + Send>
+        let dispatch_encoding = if self.json_only {
+            quote! {
+                if let Err(err) = Encoding::from_headers_accepting(&req.headers, &[Encoding::Json]) {
+                    return Box::new(future::err(err.into()));
+                }
+            }
+        } else if self.generate_json {
+            quote! {
+                let encoding = match Encoding::from_headers(&req.headers, true) {
+                    Ok(encoding) => encoding,
+                    Err(err) => return Box::new(future::err(err.into())),
+                };
+                let response_encoding = Encoding::negotiate(&req.headers, encoding);
+            }
+        } else {
+            quote! {
+                if let Err(err) = Encoding::from_headers(&req.headers, false) {
+                    return Box::new(future::err(err.into()));
+                }
+            }
+        };
+
+        let allow_json = self.generate_json;
+        let content_type_check = if self.json_only {
+            quote! {
+                if let Err(err) = Encoding::from_headers_accepting(req.headers(), &[Encoding::Json]) {
+                    return Box::new(future::result(ProstTwirpError::from(err).to_hyper_resp_with_config(&config)));
+                }
+            }
+        } else {
+            quote! {
+                if let Err(err) = Encoding::from_headers(req.headers(), #allow_json) {
+                    return Box::new(future::result(ProstTwirpError::from(err).to_hyper_resp_with_config(&config)));
+                }
+            }
+        };
+
+        // Codecs work on the serde mapping of messages, so only services with JSON support accept them
+        let (content_type_check, dispatch_call, dispatch_with_config) = if self.generate_json && !self.json_only {
+            (quote! {
+                if config.codecs.find(req.headers()).is_none() {
+                    #content_type_check
+                }
+            },
+            quote! { Self::dispatch_with_config(service, &config, method, req) },
+            quote! {
+                /// Call the given method like `dispatch`, also accepting the content types of the config's codecs
+                ///
+                /// Requests in a codec's content type are replied to with the same codec.
+                pub fn dispatch_with_config<T: 'static + #name>(service: T, config: &#module::ServerConfig,
+                    method: #method_enum, req: #module::ServiceRequest<Vec<u8>>) -> #module::PTRes<Vec<u8>>
                 {
                     use ::futures::{future, Future};
+
+                    let codec = match config.codecs.find(&req.headers) {
+                        Some(codec) => codec,
+                        None => return Self::dispatch(service, method, req),
+                    };
+                    match method {
+                        #( #codec_handlers, )*
+                    }
+                }
+            })
+        } else {
+            (content_type_check, quote! { Self::dispatch(service, method, req) }, quote! {})
+        };
+
+        // `ProstTwirpError` is large, and the user's crate has its own clippy settings
+        quote! {
+            #[allow(clippy::result_large_err)]
+            impl dyn #name {
+                /// Handle the request with the given service
+                ///
+                /// The service is moved into the response, so it must be `'static`; pass an
+                /// `Arc` of it to share its state, e.g. a connection pool, across requests.
+                pub fn server_handler<T: 'static + #name>(service: T, req: ::hyper::Request<::hyper::Body>) -> #module::FutHyperResp {
+                    Self::server_handler_with_config(service, &#module::ServerConfig::default(), req)
+                }
+
+                /// Create a hyper service factory for `Server::serve`, handling every request with the given service
+                ///
+                /// The service is shared by every connection through an `Arc`.
+                pub fn new_server<T: 'static + #name>(service: T)
+                    -> #module::TwirpServer<impl Fn(::hyper::Request<::hyper::Body>) -> #module::FutHyperResp + Send + Sync + 'static>
+                {
+                    Self::new_server_with_config(service, #module::ServerConfig::default())
+                }
+
+                /// Create a hyper service factory like `new_server`, handling requests with the given config
+                pub fn new_server_with_config<T: 'static + #name>(service: T, config: #module::ServerConfig)
+                    -> #module::TwirpServer<impl Fn(::hyper::Request<::hyper::Body>) -> #module::FutHyperResp + Send + Sync + 'static>
+                {
+                    let service = ::std::sync::Arc::new(service);
+                    let config = ::std::sync::Arc::new(config);
+                    #module::TwirpServer::new(move |req| Self::server_handler_with_shared_config(service.clone(), config.clone(), req))
+                }
+
+                /// Handle the request with the given service and config
+                ///
+                /// Paths that aren't routes of this service get a `bad_route` error; use
+                /// `try_server_handler_with_config` or a `TwirpRouter` to handle them elsewhere.
+                pub fn server_handler_with_config<T: 'static + #name>(service: T, config: &#module::ServerConfig,
+                    req: ::hyper::Request<::hyper::Body>) -> #module::FutHyperResp
+                {
+                    Self::server_handler_with_shared_config(service, ::std::sync::Arc::new(config.clone()), req)
+                }
+
+                /// Handle the request like `server_handler_with_config`, with a config shared across requests
+                pub fn server_handler_with_shared_config<T: 'static + #name>(service: T, config: ::std::sync::Arc<#module::ServerConfig>,
+                    req: ::hyper::Request<::hyper::Body>) -> #module::FutHyperResp
+                {
+                    use ::futures::future;
                     use #module::{TwirpError, ProstTwirpError};
+                    Self::try_server_handler_with_shared_config(service, config.clone(), req).unwrap_or_else(|_| {
+                        Box::new(future::result(ProstTwirpError::from(TwirpError::bad_route("RPC Path not found"))
+                            .to_hyper_resp_with_config(&config)))
+                    })
+                }
+
+                /// Handle the request if its path is a route of this service, or give it back otherwise
+                pub fn try_server_handler<T: 'static + #name>(service: T, req: ::hyper::Request<::hyper::Body>) -> #module::RouteResult {
+                    Self::try_server_handler_with_config(service, &#module::ServerConfig::default(), req)
+                }
+
+                /// Handle the request like `server_handler_with_config`, returning it as the error instead of a `bad_route` response if the path isn't a route
+                pub fn try_server_handler_with_config<T: 'static + #name>(service: T, config: &#module::ServerConfig,
+                    req: ::hyper::Request<::hyper::Body>) -> #module::RouteResult
+                {
+                    Self::try_server_handler_with_shared_config(service, ::std::sync::Arc::new(config.clone()), req)
+                }
+
+                /// Route the request like `try_server_handler_with_config`, with a config shared across requests
+                ///
+                /// Only the `Arc` is cloned for each request, not the interceptors and codecs of the config.
+                pub fn try_server_handler_with_shared_config<T: 'static + #name>(service: T, config: ::std::sync::Arc<#module::ServerConfig>,
+                    req: ::hyper::Request<::hyper::Body>) -> #module::RouteResult
+                {
+                    use ::futures::{future, Future};
+                    use #module::{Encoding, TwirpError, ProstTwirpError};
                     use ::hyper::{StatusCode, Response, Body, Method};
-                    type ResponseFuture = Box<Future<Item=Response<Body>, Error=ProstTwirpError> + Send>;
-
-                    match req.headers().get(::hyper::header::CONTENT_TYPE) {
-                        Some(ct) if ct == "application/protobuf" => (),
-                        Some(ct) if ct == "application/json" => (),
-                        _ => {
-                            return Box::new(future::ok(TwirpError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE,
-                                "bad_content_type", "Content type must be application/protobuf").to_hyper_resp()))
+                    type ResponseFuture = Box<dyn Future<Item=Response<Body>, Error=ProstTwirpError> + Send>;
+
+                    let method = match #method_enum::from_path(config.route_path(req.uri().path())) {
+                        Some(method) => method,
+                        None => {
+                            // Paths of this service with an unknown method are still this service's to reject
+                            let method = match config.route_path(req.uri().path()).strip_prefix(#service_path) {
+                                Some(method) => method.to_string(),
+                                None => return Err(req),
+                            };
+                            return Ok(Box::new(future::result(ProstTwirpError::from(TwirpError::bad_route(
+                                &format!("Method {:?} not found in service {}", method, #service_full_name))).to_hyper_resp_with_config(&config))));
+                        }
+                    };
+
+                    let mut req = req;
+                    req.extensions_mut().insert(#module::RpcMethod(method.name()));
+                    Ok(#module::instrument_server(req, move |req| -> #module::FutHyperResp {
+                        if req.method() != Method::POST {
+                            return Box::new(future::result(ProstTwirpError::from(TwirpError::new(StatusCode::METHOD_NOT_ALLOWED,
+                                "bad_route", &format!("Unsupported method {} (only POST is allowed)", req.method()))).to_hyper_resp_with_config(&config)))
                         }
-                    }
 
-                    Box::new(
-                        #module::ServiceRequest::from_hyper_raw(req).and_then(move |req| -> ResponseFuture {
-                            match (req.method.clone(), req.uri.path()) {
-                                #( #handlers, )*
-                                _ => { Box::new(future::ok(TwirpError::new(StatusCode::NOT_FOUND, "not_found", "RPC Path not found").to_hyper_resp())) }
-                            }
-                        }).or_else(|err| err.to_hyper_resp())
-                    )
+                        #content_type_check
+
+                        let error_config = ::std::sync::Arc::clone(&config);
+                        Box::new(config.read_request(req).and_then(move |req| -> ResponseFuture {
+                            let req = match config.intercept_request(req) {
+                                Ok(req) => req,
+                                Err(err) => return Box::new(future::err(err)),
+                            };
+                            let accept_encoding = req.headers.get(::hyper::header::ACCEPT_ENCODING).cloned();
+                            Box::new(config.apply_handler_timeout(#dispatch_call)
+                                .and_then(move |v| config.intercept_response(accept_encoding.as_ref(), v)))
+                        }).or_else(move |err| error_config.intercept_error(err)))
+                    }))
                 }
+
+                /// Call the given method of the service with an already read request
+                ///
+                /// The input is decoded according to the `Content-Type` of the request, and the output
+                /// encoded the same way unless the `Accept` header of a service supporting JSON asks
+                /// otherwise. None of the `ServerConfig` hooks are run.
+                pub fn dispatch<T: 'static + #name>(service: T, method: #method_enum,
+                    req: #module::ServiceRequest<Vec<u8>>) -> #module::PTRes<Vec<u8>>
+                {
+                    use ::futures::{future, Future};
+                    use #module::Encoding;
+
+                    #dispatch_encoding
+                    match method {
+                        #( #handlers, )*
+                    }
+                }
+
+                #dispatch_with_config
             }
         }
     }
@@ -151,6 +696,10 @@ impl TwirpServiceGenerator {
 
 impl TwirpServiceGenerator {
     fn render(&self, tokens: TokenStream, buf: &mut String) {
+        if !self.format {
+            return write!(buf, "{}", &tokens).unwrap();
+        }
+
         match TwirpServiceGenerator::rustfmt(&tokens) {
             Ok(formatted) => buf.write_str(&formatted).unwrap(),
             Err(_) => write!(buf, "{}", &tokens).unwrap(),
@@ -162,10 +711,11 @@ impl TwirpServiceGenerator {
         use std::io::Write;
 
         let mut rustfmt = Command::new("rustfmt")
+            .args(["--edition", "2018"])
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .spawn()
-            .map_err(|_| format!("Couldn't spawn rustfmt"))?;
+            .map_err(|_| "Couldn't spawn rustfmt".to_string())?;
 
         {
             let stdin = rustfmt
@@ -194,16 +744,174 @@ impl ServiceGenerator for TwirpServiceGenerator {
     fn generate(&mut self, service: Service, buf: &mut String) {
         let mut tokens = TokenStream::new();
 
-        tokens.extend(self.generate_type_aliases());
+        // Service modules each get their own aliases, otherwise the first service of a package does
+        if self.service_modules || self.aliased_packages.insert(service.package.clone()) {
+            tokens.extend(self.generate_type_aliases());
+        }
         tokens.extend(self.generate_main_trait(&service));
         if self.generate_client {
             tokens.extend(self.generate_client(&service));
         }
         if self.generate_server {
             // tokens.extend(self.generate_server_impl(&service));
+            tokens.extend(self.generate_method_enum(&service));
             tokens.extend(self.generate_http_handler(&service));
         }
+        if self.generate_classify {
+            tokens.extend(self.generate_classify(&service));
+        }
+
+        if self.service_modules {
+            let module_name = self.service_module_name(&service);
+            let doc = format!("The generated items of the `{}` service", service.name);
+            tokens = quote! {
+                #[doc = #doc]
+                pub mod #module_name {
+                    #tokens
+                }
+            };
+        }
 
         self.render(tokens, buf);
     }
 }
+
+#[cfg(test)]
+mod service_gen_tests {
+    use super::*;
+
+    fn comments() -> Comments {
+        Comments { leading_detached: Vec::new(), leading: Vec::new(), trailing: Vec::new() }
+    }
+
+    fn service(package: &str) -> Service {
+        Service {
+            name: "Haberdasher".to_string(),
+            proto_name: "Haberdasher".to_string(),
+            package: package.to_string(),
+            comments: comments(),
+            methods: Vec::new(),
+            options: Default::default(),
+        }
+    }
+
+    fn method() -> Method {
+        Method {
+            name: "make_hat".to_string(),
+            proto_name: "MakeHat".to_string(),
+            comments: comments(),
+            input_type: "Size".to_string(),
+            output_type: "Hat".to_string(),
+            input_proto_type: ".Size".to_string(),
+            output_proto_type: ".Hat".to_string(),
+            options: Default::default(),
+            client_streaming: false,
+            server_streaming: false,
+        }
+    }
+
+    #[test]
+    fn twirp_uri() {
+        let gen = TwirpServiceGenerator::new();
+        assert_eq!(gen.twirp_uri(&service("twitch.twirp.example"), &method()).to_string(),
+            r#""/twirp/twitch.twirp.example.Haberdasher/MakeHat""#);
+        assert_eq!(gen.twirp_uri(&service(""), &method()).to_string(), r#""/twirp/Haberdasher/MakeHat""#);
+    }
+
+    #[test]
+    fn well_known_types() {
+        let mut gen = TwirpServiceGenerator::new();
+        let empty = ("super::google::protobuf::Empty", ".google.protobuf.Empty");
+        let timestamp = ("super::google::protobuf::Timestamp", ".google.protobuf.Timestamp");
+        assert_eq!(gen.message_type(empty.0, empty.1).to_string(), "( )");
+        assert_eq!(gen.message_type(timestamp.0, timestamp.1).to_string(), ":: prost_types :: Timestamp");
+        assert_eq!(gen.message_type("Size", ".Size").to_string(), "Size");
+        assert_eq!(gen.message_type("super::wardrobe::Outfit", ".pkg.wardrobe.Outfit").to_string(),
+            "super :: wardrobe :: Outfit");
+
+        gen.prost_types = false;
+        assert_eq!(gen.message_type(empty.0, empty.1).to_string(), "super :: google :: protobuf :: Empty");
+    }
+
+    #[test]
+    fn method_enum() {
+        let mut gen = TwirpServiceGenerator { format: false, ..TwirpServiceGenerator::new() };
+        let mut service = service("pkg");
+        service.methods.push(method());
+        let mut buf = String::new();
+        gen.generate(service, &mut buf);
+        assert!(buf.contains("pub enum HaberdasherMethod { MakeHat , }"));
+        assert!(buf.contains(r#""/twirp/pkg.Haberdasher/MakeHat" => Some ( HaberdasherMethod :: MakeHat )"#));
+        assert!(buf.contains("pub fn dispatch"));
+    }
+
+    #[test]
+    fn service_modules() {
+        let mut gen = TwirpServiceGenerator { format: false, service_modules: true, ..TwirpServiceGenerator::both() };
+        let mut haberdasher = service("pkg");
+        haberdasher.methods.push(method());
+        let mut buf = String::new();
+        gen.generate(haberdasher, &mut buf);
+        assert!(buf.starts_with("# [ doc = \"The generated items of the `Haberdasher` service\" ] pub mod haberdasher {"));
+        assert!(buf.contains("pub type PTReq < I >"));
+        assert!(buf.contains("fn make_hat ( & self , i : :: twirp_rs :: PTReq < super :: Size > )"));
+        assert_eq!(gen.message_type("::prost_types::Any", ".pkg.Any").to_string(), ":: prost_types :: Any");
+
+        let module_name = |name: &str| gen.service_module_name(&Service { name: name.to_string(), ..service("pkg") });
+        assert_eq!(module_name("Haberdasher").to_string(), "haberdasher");
+        assert_eq!(module_name("HatShop2Service").to_string(), "hat_shop2_service");
+        assert_eq!(module_name("HTTPProxy").to_string(), "http_proxy");
+    }
+
+    #[test]
+    fn services_of_one_module() {
+        for &format in &[false, true] {
+            let mut gen = TwirpServiceGenerator { format, ..TwirpServiceGenerator::both() };
+            let mut buf = String::new();
+            gen.generate(service("pkg"), &mut buf);
+            gen.generate(Service { name: "Tailor".to_string(), proto_name: "Tailor".to_string(), ..service("pkg") }, &mut buf);
+            assert!(buf.contains("Tailor"));
+            assert_eq!(buf.matches("PTReq<I> =").count() + buf.matches("PTReq < I > =").count(), 1);
+
+            // Other packages are other modules, whatever their buffer already holds
+            let mut buf = "// pub type PTReq<I> = ...\n".to_string();
+            gen.generate(service("other"), &mut buf);
+            assert_eq!(buf.matches("PTReq<I> =").count() + buf.matches("PTReq < I > =").count(), 2);
+        }
+    }
+
+    #[test]
+    fn rpc_paths() {
+        let mut gen = TwirpServiceGenerator { format: false, generate_server: false, ..TwirpServiceGenerator::new() };
+        let mut service = service("pkg");
+        service.methods.push(method());
+        let mut buf = String::new();
+        gen.generate(service, &mut buf);
+        assert!(buf.contains(r#"& [ ( "MakeHat" , "/twirp/pkg.Haberdasher/MakeHat" ) ]"#));
+    }
+
+    #[test]
+    fn json_only() {
+        let mut gen = TwirpServiceGenerator { format: false, json_only: true, ..TwirpServiceGenerator::both() };
+        let mut haberdasher = service("pkg");
+        haberdasher.methods.push(method());
+        let mut buf = String::new();
+        gen.generate(haberdasher, &mut buf);
+        assert!(buf.contains("Encoding :: from_headers_accepting ( req . headers ( ) , & [ Encoding :: Json ] )"));
+        assert!(buf.contains("req . to_proto_json ( )") && buf.contains("v . to_json_raw ( )"));
+        assert!(!buf.contains("req . to_proto ( )") && !buf.contains("dispatch_with_config"));
+    }
+
+    #[test]
+    fn classify() {
+        let mut gen = TwirpServiceGenerator { format: false, ..Default::default() };
+        let mut buf = String::new();
+        gen.generate(service("pkg"), &mut buf);
+        assert!(!buf.contains("classify"));
+
+        gen.generate_classify = true;
+        buf.clear();
+        gen.generate(service("pkg"), &mut buf);
+        assert!(buf.contains("pub fn classify"));
+    }
+}
@@ -0,0 +1,102 @@
+use futures::future;
+use prost::Message;
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::service_run::{PTRes, ProstTwirpError, ServiceRequest, ServiceResponse, TwirpClient, TwirpError};
+
+type MockHandler = Arc<dyn Fn(ServiceRequest<Vec<u8>>) -> Result<ServiceResponse<Vec<u8>>, ProstTwirpError> + Send + Sync>;
+
+/// An in-memory client for testing code that uses generated clients, without any network
+///
+/// Responses are registered per Twirp path, e.g. `/twirp/twitch.twirp.example.Haberdasher/MakeHat`,
+/// and the client can back a generated client like `HaberdasherClient(mock)`. Requests and
+/// responses still go through protobuf encoding. Paths without a registered response fail with a
+/// `bad_route` error.
+#[derive(Clone, Default)]
+pub struct MockClient {
+    handlers: HashMap<String, MockHandler>,
+}
+
+impl MockClient {
+    /// Create a client with no registered responses
+    pub fn new() -> MockClient {
+        MockClient::default()
+    }
+
+    /// Answer requests for the given path with the given closure
+    pub fn handle<I, O, F>(mut self, path: &str, handler: F) -> MockClient
+            where I: Message + Default + 'static, O: Message + Default + 'static,
+                  F: Fn(ServiceRequest<I>) -> Result<ServiceResponse<O>, ProstTwirpError> + Send + Sync + 'static {
+        self.handlers.insert(MockClient::key(path), Arc::new(move |req| {
+            handler(req.to_proto()?)?.to_proto_raw()
+        }));
+        self
+    }
+
+    /// Answer requests for the given path with the given output
+    pub fn respond<O: Message + Default + 'static>(mut self, path: &str, output: O) -> MockClient {
+        let resp = ServiceResponse::new(output).to_proto_raw();
+        self.handlers.insert(MockClient::key(path), Arc::new(move |_| match resp {
+            Ok(ref resp) => Ok(resp.clone()),
+            Err(_) => Err(TwirpError::internal("Mock response failed to encode").into()),
+        }));
+        self
+    }
+
+    /// Fail requests for the given path with the given error
+    pub fn fail(mut self, path: &str, err: TwirpError) -> MockClient {
+        self.handlers.insert(MockClient::key(path), Arc::new(move |_| Err(err.clone().into())));
+        self
+    }
+
+    fn key(path: &str) -> String {
+        path.trim_start_matches('/').to_string()
+    }
+}
+
+impl TwirpClient for MockClient {
+    fn go<I, O>(&self, path: &str, req: ServiceRequest<I>) -> PTRes<O>
+            where I: Message + Default + Clone + 'static, O: Message + Default + 'static {
+        let handler = match self.handlers.get(&MockClient::key(path)) {
+            Some(handler) => handler,
+            None => return Box::new(future::err(TwirpError::bad_route(&format!("No mock response for {}", path)).into())),
+        };
+        Box::new(future::result(req.to_proto_raw()
+            .and_then(|req| handler(req))
+            .and_then(|resp| resp.to_proto())))
+    }
+}
+
+#[cfg(test)]
+mod mock_client_tests {
+    use super::*;
+    use futures::Future;
+    use prost_derive::Message;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Size {
+        #[prost(int32, tag="1")]
+        inches: i32,
+    }
+
+    #[test]
+    fn registered_responses() {
+        let client = MockClient::new()
+            .respond("/twirp/pkg.Svc/Fixed", Size { inches: 3 })
+            .handle("/twirp/pkg.Svc/Double", |req: ServiceRequest<Size>| {
+                Ok(ServiceResponse::new(Size { inches: req.input.inches * 2 }))
+            })
+            .fail("/twirp/pkg.Svc/Fail", TwirpError::unavailable("Try later"));
+
+        let go = |path| client.go::<Size, Size>(path, ServiceRequest::new(Size { inches: 10 })).wait();
+        assert_eq!(go("/twirp/pkg.Svc/Fixed").unwrap().output, Size { inches: 3 });
+        assert_eq!(go("/twirp/pkg.Svc/Double").unwrap().output, Size { inches: 20 });
+
+        for (path, code) in &[("/twirp/pkg.Svc/Fail", "unavailable"), ("/twirp/pkg.Svc/Missing", "bad_route")] {
+            match go(path).unwrap_err() {
+                ProstTwirpError::TwirpError(err) => assert_eq!(err.code, *code),
+                err => panic!("unexpected error: {:?}", err),
+            }
+        }
+    }
+}
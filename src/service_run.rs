@@ -1,22 +1,169 @@
+use bytes::Bytes;
 use futures::{Future, Stream};
-use futures::future;
-use hyper;
-use hyper::{Body, Client, HeaderMap, Version, Method, Request, Response, StatusCode, Uri};
+use futures::{future, stream};
+use hyper::{Body, Chunk, Client, HeaderMap, Version, Method, Request, Response, StatusCode, Uri};
 use hyper::client::HttpConnector;
-use hyper::header::{HeaderValue, CONTENT_TYPE, CONTENT_LENGTH};
+use hyper::client::connect::Connect;
+use hyper::header::{HeaderValue, IntoHeaderName, CONTENT_ENCODING, CONTENT_TYPE, CONTENT_LENGTH, USER_AGENT};
 use prost::{DecodeError, EncodeError, Message};
+use serde::de::DeserializeOwned;
 use serde_derive::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+#[cfg(feature = "compression")]
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_timer::{Delay, Timeout};
 
-pub type FutReq<T> = Box<Future<Item=ServiceRequest<T>, Error=ProstTwirpError> + Send>;
+/// The header a client sends its timeout in, as a number of milliseconds
+///
+/// `HyperClient` sends it with every request that has a timeout, unless disabled with
+/// `with_deadline_header(false)`, and servers expose it as `ServiceRequest::deadline`.
+pub const TIMEOUT_HEADER: &str = "twirp-timeout-ms";
+
+/// The largest body buffer allocated upfront from an unbounded `Content-Length`
+const MAX_PREALLOCATED_BODY: usize = 16 * 1024 * 1024;
+
+/// The size of the chunks of streamed response bodies
+const STREAMING_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The headers whose values `Debug` output of requests and responses hides by default
+pub const DEFAULT_REDACTED_HEADERS: &[&str] = &["authorization", "cookie", "set-cookie"];
+
+/// The most characters of a body's `Debug` output shown in a request or response's `Debug` output
+const MAX_DEBUG_BODY_CHARS: usize = 256;
+
+/// The headers whose values the `debug_with` output of requests and responses hides
+///
+/// The plain `Debug` output hides `DEFAULT_REDACTED_HEADERS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedactionConfig {
+    /// The lowercased header names
+    names: Vec<String>,
+}
+
+impl RedactionConfig {
+    /// Hide the given headers, matched case-insensitively
+    ///
+    /// The names replace `DEFAULT_REDACTED_HEADERS`, so include those to add to them, e.g. an API key header.
+    pub fn new(names: &[&str]) -> RedactionConfig {
+        RedactionConfig { names: names.iter().map(|name| name.to_ascii_lowercase()).collect() }
+    }
+
+    /// Whether the value of the given lowercased header is hidden
+    pub fn is_redacted(&self, name: &str) -> bool {
+        self.names.iter().any(|n| n == name)
+    }
+}
+
+impl Default for RedactionConfig {
+    fn default() -> RedactionConfig { RedactionConfig::new(DEFAULT_REDACTED_HEADERS) }
+}
+
+/// Headers formatted with the values of redacted headers hidden, `None` for the defaults
+struct RedactedHeaders<'a>(&'a HeaderMap<HeaderValue>, Option<&'a RedactionConfig>);
+
+impl<'a> fmt::Debug for RedactedHeaders<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (name, value) in self.0 {
+            let redacted = match self.1 {
+                Some(redaction) => redaction.is_redacted(name.as_str()),
+                None => DEFAULT_REDACTED_HEADERS.contains(&name.as_str()),
+            };
+            if redacted {
+                map.entry(name, &format_args!("[redacted]"));
+            } else {
+                map.entry(name, value);
+            }
+        }
+        map.finish()
+    }
+}
+
+/// `Debug` output with the given redacted headers, `None` for the defaults
+trait FmtRedacted {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter, redaction: Option<&RedactionConfig>) -> fmt::Result;
+}
+
+/// A request or response formatted with the given redacted headers
+struct WithRedaction<'a, T>(&'a T, &'a RedactionConfig);
+
+impl<'a, T: FmtRedacted> fmt::Debug for WithRedaction<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.0.fmt_redacted(f, Some(self.1)) }
+}
+
+/// A body formatted with its `Debug` output cut to `MAX_DEBUG_BODY_CHARS`
+struct TruncatedBody<'a, T>(&'a T);
+
+impl<'a, T: fmt::Debug> fmt::Debug for TruncatedBody<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let body = format!("{:?}", self.0);
+        match body.char_indices().nth(MAX_DEBUG_BODY_CHARS) {
+            Some((end, _)) => write!(f, "{}... ({} more chars)", &body[..end], body[end..].chars().count()),
+            None => f.write_str(&body),
+        }
+    }
+}
+
+pub type FutReq<T> = Box<dyn Future<Item=ServiceRequest<T>, Error=ProstTwirpError> + Send>;
 
 /// The type of every service request 
 pub type PTReq<I> = ServiceRequest<I>;
 
 /// The type of every service response
-pub type PTRes<O> = Box<Future<Item=ServiceResponse<O>, Error=ProstTwirpError> + Send>;
+///
+/// The `Future` trait is `#[must_use]`, so dropping one of these boxed futures without polling it,
+/// e.g. a forgotten generated client call, already warns.
+pub type PTRes<O> = Box<dyn Future<Item=ServiceResponse<O>, Error=ProstTwirpError> + Send>;
+
+/// The type of every hyper response returned by a server handler
+pub type FutHyperResp = Box<dyn Future<Item=Response<Body>, Error=hyper::Error> + Send>;
+
+/// The result of trying to route a request to a service
+///
+/// Routes that don't belong to the service give the request back, so another service can try it.
+pub type RouteResult = Result<FutHyperResp, Request<Body>>;
+
+/// A service response with the given output, e.g. `return twirp_ok(hat)` in a service method
+pub fn twirp_ok<O: Send + 'static>(output: O) -> PTRes<O> {
+    Box::new(future::ok(ServiceResponse::new(output)))
+}
+
+/// A failed service response, e.g. `return twirp_err(TwirpError::not_found("No such hat"))` in a service method
+pub fn twirp_err<O: Send + 'static>(err: TwirpError) -> PTRes<O> {
+    Box::new(future::err(err.into()))
+}
+
+/// Turn an output message or response into a finished service response
+///
+/// This tidies service methods, e.g. `Hat { .. }.into_res()` instead of
+/// `Box::new(future::ok(Hat { .. }.into()))`. `PTRes` is a boxed future alias, so it can't have a
+/// `From` impl.
+pub trait IntoRes<O> {
+    /// The successful service response
+    fn into_res(self) -> PTRes<O>;
+}
+
+impl<O: Message + Send + 'static> IntoRes<O> for O {
+    fn into_res(self) -> PTRes<O> {
+        twirp_ok(self)
+    }
+}
+
+impl<O: Send + 'static> IntoRes<O> for ServiceResponse<O> {
+    fn into_res(self) -> PTRes<O> {
+        Box::new(future::ok(self))
+    }
+}
 
 /// A request with HTTP info and the serialized input object
-#[derive(Debug)]
+///
+/// `Debug` output hides the values of `DEFAULT_REDACTED_HEADERS`, or others with `debug_with`, and
+/// truncates the input, so requests can be logged.
+#[derive(Clone)]
 pub struct ServiceRequest<T> {
     /// The URI of the original request
     /// 
@@ -29,17 +176,269 @@ pub struct ServiceRequest<T> {
     /// The set of headers
     ///
     /// Should always at least have `Content-Type`. Clients will override `Content-Length` on serialization.
+    /// Servers keep the original request headers when decoding the input, so service methods can read them.
     pub headers: HeaderMap<HeaderValue>,
     // The serialized request object
     pub input: T,
+    /// When the client gives up on the request, read by servers from `TIMEOUT_HEADER`
+    deadline: Option<Instant>,
+    /// The name of the RPC method the request was routed to, set by generated servers
+    rpc_method: Option<&'static str>,
+}
+
+/// The name of the RPC method a request was routed to, as written in the proto file, e.g. `MakeHat`
+///
+/// Generated server handlers add it to the extensions of every hyper request they route, before
+/// `instrument_server`, and it is then available as `ServiceRequest::rpc_method`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RpcMethod(pub &'static str);
+
+/// The encoding of a request or response body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Binary protobuf, `application/protobuf`
+    Protobuf,
+    /// JSON, `application/json`
+    Json,
+}
+
+impl Encoding {
+    /// Find the encoding for the given `Content-Type` header value
+    ///
+    /// Parameters such as `charset` are ignored and the media type is matched case-insensitively.
+    pub fn from_content_type(ct: &HeaderValue) -> Option<Encoding> {
+        let ct = ct.to_str().ok()?;
+        Encoding::from_media_type(ct.split(';').next().unwrap_or(""))
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Encoding> {
+        let media_type = media_type.trim();
+        if media_type.eq_ignore_ascii_case("application/protobuf") {
+            Some(Encoding::Protobuf)
+        } else if media_type.eq_ignore_ascii_case("application/json") {
+            Some(Encoding::Json)
+        } else {
+            None
+        }
+    }
+
+    /// Find the encoding of a request from its headers, accepting JSON only if `allow_json` is set
+    ///
+    /// A missing or empty `Content-Type` is a `missing_content_type` error, and any other type this
+    /// can't decode is a `bad_content_type` error, both with a `415 Unsupported Media Type` status.
+    pub fn from_headers(headers: &HeaderMap<HeaderValue>, allow_json: bool) -> Result<Encoding, TwirpError> {
+        let accepted: &[Encoding] = if allow_json { &[Encoding::Protobuf, Encoding::Json] } else { &[Encoding::Protobuf] };
+        Encoding::from_headers_accepting(headers, accepted)
+    }
+
+    /// Find the encoding of a request from its headers like `from_headers`, accepting only the given encodings
+    ///
+    /// This is e.g. `&[Encoding::Json]` for JSON-only servers, which reject protobuf requests with
+    /// a `bad_content_type` error.
+    pub fn from_headers_accepting(headers: &HeaderMap<HeaderValue>, accepted: &[Encoding]) -> Result<Encoding, TwirpError> {
+        let ct = match headers.get(CONTENT_TYPE) {
+            Some(ct) if !ct.as_bytes().iter().all(u8::is_ascii_whitespace) => ct,
+            _ => return Err(TwirpError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "missing_content_type",
+                "Content type is missing")),
+        };
+        match Encoding::from_content_type(ct) {
+            Some(encoding) if accepted.contains(&encoding) => return Ok(encoding),
+            _ => (),
+        }
+        let expected: Vec<_> = accepted.iter().map(|encoding| encoding.media_type()).collect();
+        Err(TwirpError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "bad_content_type",
+            &format!("Unsupported content type {}, must be {}", ct.to_str().unwrap_or("?"), expected.join(" or "))))
+    }
+
+    /// Choose the encoding of a response from the `Accept` header of its request
+    ///
+    /// The supported type with the highest quality wins, with ties going to the encoding of the
+    /// request. Without an `Accept` header, or one that only has wildcards, other types or
+    /// malformed entries, the response uses the encoding of the request.
+    pub fn negotiate(headers: &HeaderMap<HeaderValue>, request_encoding: Encoding) -> Encoding {
+        let accept = match headers.get(hyper::header::ACCEPT).and_then(|v| v.to_str().ok()) {
+            Some(accept) => accept,
+            None => return request_encoding,
+        };
+        let mut best: Option<(f32, Encoding)> = None;
+        for range in accept.split(',') {
+            let mut params = range.split(';');
+            let encoding = match params.next().and_then(Encoding::from_media_type) {
+                Some(encoding) => encoding,
+                None => continue,
+            };
+            let quality = match params.filter_map(|p| p.trim().strip_prefix("q=")).next() {
+                Some(q) => match q.trim().parse::<f32>() {
+                    Ok(q) if (0.0..=1.0).contains(&q) => q,
+                    _ => continue,
+                },
+                None => 1.0,
+            };
+            let better = match best {
+                Some((best_quality, _)) => quality > best_quality || (quality == best_quality && encoding == request_encoding),
+                None => quality > 0.0,
+            };
+            if better {
+                best = Some((quality, encoding));
+            }
+        }
+        best.map_or(request_encoding, |(_, encoding)| encoding)
+    }
+
+    /// The `Content-Type` header value for this encoding
+    pub fn content_type(self) -> HeaderValue {
+        HeaderValue::from_static(self.media_type())
+    }
+
+    /// The media type of this encoding, e.g. `application/protobuf`
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Encoding::Protobuf => "application/protobuf",
+            Encoding::Json => "application/json",
+        }
+    }
+}
+
+/// A body encoding for content types beyond the built-in protobuf and JSON ones, e.g. msgpack
+///
+/// Codecs work on the JSON mapping of messages, so like JSON support they need messages with
+/// serde derives; protobuf itself is always handled natively since it needs the message schema.
+/// Register codecs in a `CodecRegistry` for servers, or pass one to `HyperClient::go_codec`.
+pub trait Codec: Send + Sync {
+    /// The media type the codec handles, e.g. `application/msgpack`
+    fn media_type(&self) -> &str;
+
+    /// Encode a message, given as its JSON mapping
+    fn encode(&self, value: &serde_json::Value) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>>;
+
+    /// Decode a non-empty body into the JSON mapping of a message
+    fn decode(&self, body: &[u8]) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>>;
+}
+
+/// The built-in JSON encoding as a `Codec`, e.g. as a reference for writing others
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn media_type(&self) -> &str {
+        "application/json"
+    }
+
+    fn encode(&self, value: &serde_json::Value) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode(&self, body: &[u8]) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+        Ok(serde_json::from_slice(body)?)
+    }
+}
+
+/// The codecs a server accepts in addition to the built-in encodings, found by `Content-Type`
+///
+/// Codecs are tried before the built-in encodings, so registering one for `application/json`
+/// replaces the built-in JSON handling. The first codec registered for a media type wins.
+#[derive(Clone, Default)]
+pub struct CodecRegistry {
+    codecs: Vec<Arc<dyn Codec>>,
+}
+
+impl CodecRegistry {
+    /// Create a registry without any codecs
+    pub fn new() -> CodecRegistry {
+        CodecRegistry::default()
+    }
+
+    /// Add a codec
+    pub fn with_codec<C: Codec + 'static>(mut self, codec: C) -> CodecRegistry {
+        self.codecs.push(Arc::new(codec));
+        self
+    }
+
+    /// Whether the registry has no codecs
+    pub fn is_empty(&self) -> bool {
+        self.codecs.is_empty()
+    }
+
+    /// The codec for the `Content-Type` in the given headers, if any
+    ///
+    /// Media types are matched case-insensitively, ignoring parameters such as `charset`.
+    pub fn find(&self, headers: &HeaderMap<HeaderValue>) -> Option<Arc<dyn Codec>> {
+        let ct = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        let media_type = ct.split(';').next().unwrap_or("").trim();
+        self.codecs.iter().find(|codec| codec.media_type().eq_ignore_ascii_case(media_type)).cloned()
+    }
+}
+
+/// Encode a message with the given codec
+fn codec_encode<T: serde::Serialize>(codec: &dyn Codec, value: &T) -> Result<Vec<u8>, ProstTwirpError> {
+    let value = serde_json::to_value(value).map_err(ProstTwirpError::JsonEncodeError)?;
+    codec.encode(&value).map_err(|err| TwirpError::internal(
+        &format!("Failed to encode {} body: {}", codec.media_type(), err)).into())
+}
+
+/// Decode a message with the given codec, an empty body being the default message
+fn codec_decode<T: Default + DeserializeOwned>(codec: &dyn Codec, body: &[u8]) -> Result<T, ProstTwirpError> {
+    if body.is_empty() {
+        return Ok(T::default());
+    }
+    let value = codec.decode(body).map_err(|err| ProstTwirpError::from(TwirpError::malformed(
+        &format!("Failed to decode {} body: {}", codec.media_type(), err))))?;
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Decode a body according to the `Content-Encoding` in the given headers, removing the header
+///
+/// Only `gzip` is supported, and only with the `compression` feature. Bodies decoding to more than
+/// `max_size` bytes, if given, fail with a `resource_exhausted` error with the `too_large` message.
+#[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+fn decode_content(headers: &mut HeaderMap<HeaderValue>, body: Vec<u8>, max_size: Option<usize>, too_large: &str)
+        -> Result<Vec<u8>, ProstTwirpError> {
+    let coding = match headers.remove(CONTENT_ENCODING) {
+        Some(coding) => coding,
+        None => return Ok(body),
+    };
+    if coding.as_bytes().eq_ignore_ascii_case(b"identity") {
+        return Ok(body);
+    }
+    #[cfg(feature = "compression")]
+    {
+        if coding.as_bytes().eq_ignore_ascii_case(b"gzip") {
+            // Stop inflating one byte past the limit, so gzip bombs can't exhaust memory
+            let mut decoded = Vec::new();
+            let limit = max_size.map_or(u64::MAX, |max| max as u64 + 1);
+            return match flate2::read::GzDecoder::new(&body[..]).take(limit).read_to_end(&mut decoded) {
+                Ok(_) if max_size.is_some_and(|max| decoded.len() > max) =>
+                    Err(TwirpError::resource_exhausted(too_large).into()),
+                Ok(_) => Ok(decoded),
+                Err(_) => Err(TwirpError::malformed("Invalid gzip body").into()),
+            };
+        }
+    }
+    Err(TwirpError::malformed(&format!("Unsupported content encoding: {}", coding.to_str().unwrap_or("?"))).into())
 }
 
-fn application_proto() -> HeaderValue {
-    HeaderValue::from_static("application/protobuf")
+/// Gzip a body, setting the `Content-Encoding` in the given headers
+#[cfg(feature = "compression")]
+fn gzip_content(headers: &mut HeaderMap<HeaderValue>, body: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    // Writing to a Vec can't fail
+    encoder.write_all(body).and_then(|_| encoder.finish()).expect("gzip into a Vec")
 }
 
-fn application_json() -> HeaderValue {
-    HeaderValue::from_static("application/json")
+/// Whether the given `Accept-Encoding` header value lists `gzip`, ignoring `q=0` entries
+#[cfg(feature = "compression")]
+fn accepts_gzip(accept_encoding: &HeaderValue) -> bool {
+    let accept_encoding = match accept_encoding.to_str() {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    accept_encoding.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("");
+        (name.eq_ignore_ascii_case("gzip") || name == "*") &&
+            !parts.any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0))
+    })
 }
 
 impl<T> ServiceRequest<T> {
@@ -48,20 +447,98 @@ impl<T> ServiceRequest<T> {
     /// This automatically sets the `Content-Type` header as `application/protobuf`.
     pub fn new(input: T) -> ServiceRequest<T> {
         let mut headers = HeaderMap::new();
-        headers.insert(CONTENT_TYPE, application_proto());
+        headers.insert(CONTENT_TYPE, Encoding::Protobuf.content_type());
         ServiceRequest {
             uri: Default::default(),
             method: Method::POST,
             version: Version::default(),
-            headers: headers,
-            input
+            headers,
+            input,
+            deadline: None,
+            rpc_method: None,
         }
     }
     
+    /// Create a service request with the given input object and headers, e.g. for auth or tracing
+    ///
+    /// `Content-Type` is set as `application/protobuf` unless the headers already set it.
+    pub fn with_headers(input: T, mut headers: HeaderMap<HeaderValue>) -> ServiceRequest<T> {
+        headers.entry(CONTENT_TYPE).expect("valid header name").or_insert_with(|| Encoding::Protobuf.content_type());
+        ServiceRequest { headers, ..ServiceRequest::new(input) }
+    }
+
     /// Copy this request with a different input value
     pub fn clone_with_input<U>(&self, input: U) -> ServiceRequest<U> {
         ServiceRequest { uri: self.uri.clone(), method: self.method.clone(), version: self.version,
-            headers: self.headers.clone(), input }
+            headers: self.headers.clone(), input, deadline: self.deadline, rpc_method: self.rpc_method }
+    }
+
+    /// The name of the RPC method the request was routed to by a generated server, e.g. `MakeHat`
+    ///
+    /// This is for interceptors and logs, which otherwise only see the path.
+    pub fn rpc_method(&self) -> Option<&'static str> {
+        self.rpc_method
+    }
+
+    /// The query string of the request URI, if any, e.g. `trace=1`
+    ///
+    /// Twirp doesn't use query strings, and routing ignores them, but debugging tools and gateways
+    /// sometimes append them.
+    pub fn query(&self) -> Option<&str> {
+        self.uri.query()
+    }
+
+    /// When the client gives up on the request, if it sent its timeout in `TIMEOUT_HEADER`
+    ///
+    /// The deadline is relative to when the server started reading the request, so clocks don't
+    /// need to be in sync. Handlers can use it to abort work nobody will wait for.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The time left until the deadline, zero once it is past
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// The value of the given header, if present and valid UTF-8
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    /// The token of a `Bearer` `Authorization` header, if any
+    pub fn bearer_token(&self) -> Option<&str> {
+        let auth = self.header("authorization")?;
+        if auth.len() > 7 && auth[..7].eq_ignore_ascii_case("bearer ") {
+            Some(auth[7..].trim())
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: fmt::Debug> ServiceRequest<T> {
+    /// `Debug` output of the request hiding the values of the given headers
+    pub fn debug_with<'a>(&'a self, redaction: &'a RedactionConfig) -> impl fmt::Debug + 'a {
+        WithRedaction(self, redaction)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for ServiceRequest<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.fmt_redacted(f, None) }
+}
+
+impl<T: fmt::Debug> FmtRedacted for ServiceRequest<T> {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter, redaction: Option<&RedactionConfig>) -> fmt::Result {
+        f.debug_struct("ServiceRequest")
+            .field("uri", &self.uri)
+            .field("method", &self.method)
+            .field("version", &self.version)
+            .field("headers", &RedactedHeaders(&self.headers, redaction))
+            .field("input", &TruncatedBody(&self.input))
+            .field("deadline", &self.deadline)
+            .field("rpc_method", &self.rpc_method)
+            .finish()
     }
 }
 
@@ -72,26 +549,59 @@ impl<T: Message + Default + 'static> From<T> for ServiceRequest<T> {
 impl ServiceRequest<Vec<u8>> {
     /// Turn a hyper request to a boxed future of a byte-array service request
     pub fn from_hyper_raw(req: Request<Body>) -> FutReq<Vec<u8>> {
+        ServiceRequest::from_hyper_raw_with_limit(req, None)
+    }
+
+    /// Turn a hyper request to a boxed future of a byte-array service request, reading at most
+    /// `max_body_size` bytes of body if given
+    ///
+    /// Larger bodies fail with a `resource_exhausted` error as soon as the limit is crossed, either
+    /// by the `Content-Length` or while the body is streamed, so they are never fully buffered.
+    ///
+    /// The body buffer is allocated upfront from the `Content-Length`, up to `max_body_size` or
+    /// 16 MiB without a limit, so large bodies aren't reallocated as they arrive.
+    pub fn from_hyper_raw_with_limit(req: Request<Body>, max_body_size: Option<usize>) -> FutReq<Vec<u8>> {
+        let too_large = || ProstTwirpError::from(TwirpError::resource_exhausted("Request body too large"));
+        let content_length = req.headers().get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+        if let (Some(len), Some(max_body_size)) = (content_length, max_body_size) {
+            if len > max_body_size as u64 {
+                return Box::new(future::err(too_large()));
+            }
+        }
+        let capacity = content_length.map_or(0, |len| {
+            len.min(max_body_size.unwrap_or(MAX_PREALLOCATED_BODY) as u64) as usize
+        });
+
         let uri = req.uri().clone();
         let method = req.method().clone();
         let version = req.version();
         let headers = req.headers().clone();
-        Box::new(req.into_body().concat2().map_err(ProstTwirpError::HyperError).map(move |body| {
-            ServiceRequest { uri, method, version, headers, input: body.to_vec() }
+        let deadline = headers.get(TIMEOUT_HEADER).and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()).and_then(|ms| Instant::now().checked_add(Duration::from_millis(ms)));
+        let rpc_method = req.extensions().get::<RpcMethod>().map(|method| method.0);
+        Box::new(req.into_body().map_err(ProstTwirpError::HyperError).fold(Vec::with_capacity(capacity), move |mut body, chunk| {
+            if max_body_size.is_some_and(|max| body.len() + chunk.len() > max) {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        }).map(move |input| {
+            ServiceRequest { uri, method, version, headers, input, deadline, rpc_method }
         }))
     }
 
     /// Turn a byte-array service request into a hyper request
-    pub fn to_hyper_raw(&self) -> Request<Body> {
+    pub fn to_hyper_raw(&self) -> Result<Request<Body>, ProstTwirpError> {
         let mut req = Request::builder()
             .method("POST")
             .uri(self.uri.clone())
-            .body(Body::from(self.input.clone()))
-            .unwrap();
+            .version(self.version)
+            .body(Body::from(self.input.clone()))?;
 
         req.headers_mut().clone_from(&self.headers);
         req.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(self.input.len() as u64));
-        req
+        Ok(req)
     }
 
     /// Turn a byte-array service request into a `AfterBodyError`-wrapped version of the given error
@@ -109,6 +619,64 @@ impl ServiceRequest<Vec<u8>> {
             Err(err) => Err(self.body_err(ProstTwirpError::ProstDecodeError(err)))
         }
     }
+
+    /// Deserialize the byte-array service request from JSON into a protobuf service request
+    ///
+    /// An empty body is treated as a default message, as per the Twirp spec.
+    pub fn to_proto_json<T: Message + Default + DeserializeOwned + 'static>(&self) -> Result<ServiceRequest<T>, ProstTwirpError> {
+        if self.input.is_empty() {
+            return Ok(self.clone_with_input(T::default()));
+        }
+        match serde_json::from_slice(&self.input) {
+            Ok(v) => Ok(self.clone_with_input(v)),
+            Err(err) => Err(self.body_err(ProstTwirpError::JsonDecodeError(err)))
+        }
+    }
+
+    /// Decode the byte-array service request with the given codec into a protobuf service request
+    ///
+    /// An empty body is treated as a default message, like for JSON.
+    pub fn to_proto_codec<T: Message + Default + DeserializeOwned + 'static>(&self, codec: &dyn Codec) -> Result<ServiceRequest<T>, ProstTwirpError> {
+        match codec_decode(codec, &self.input) {
+            Ok(v) => Ok(self.clone_with_input(v)),
+            Err(err) => Err(self.body_err(err))
+        }
+    }
+
+    /// Check that the body has as many bytes as the `Content-Length` says, if there is one
+    pub fn check_content_length(&self) -> Result<(), ProstTwirpError> {
+        let content_length = match self.headers.get(CONTENT_LENGTH) {
+            Some(content_length) => content_length,
+            None => return Ok(()),
+        };
+        match content_length.to_str().ok().and_then(|v| v.parse::<u64>().ok()) {
+            Some(len) if len == self.input.len() as u64 => Ok(()),
+            Some(len) => Err(TwirpError::malformed(&format!("Request body has {} bytes but its Content-Length is {}",
+                self.input.len(), len)).into()),
+            None => Err(TwirpError::malformed("Invalid Content-Length").into()),
+        }
+    }
+
+    /// Decode the body according to its `Content-Encoding`, e.g. a gzipped body
+    pub fn decompress(self) -> Result<ServiceRequest<Vec<u8>>, ProstTwirpError> {
+        self.decompress_with_limit(None)
+    }
+
+    /// Decode the body like `decompress`, failing with a `resource_exhausted` error if it decodes
+    /// to more than `max_body_size` bytes
+    pub fn decompress_with_limit(mut self, max_body_size: Option<usize>) -> Result<ServiceRequest<Vec<u8>>, ProstTwirpError> {
+        self.input = decode_content(&mut self.headers, self.input, max_body_size, "Request body too large")?;
+        Ok(self)
+    }
+
+    /// Gzip the body and advertise gzip support for the response
+    #[cfg(feature = "compression")]
+    pub fn compress(mut self) -> ServiceRequest<Vec<u8>> {
+        self.input = gzip_content(&mut self.headers, &self.input);
+        self.headers.entry(hyper::header::ACCEPT_ENCODING).expect("valid header name")
+            .or_insert(HeaderValue::from_static("gzip"));
+        self
+    }
 }
 
 impl<T: Message + Default + 'static> ServiceRequest<T> {
@@ -129,12 +697,25 @@ impl<T: Message + Default + 'static> ServiceRequest<T> {
 
     /// Turn a protobuf service request into a hyper request
     pub fn to_hyper_proto(&self) -> Result<Request<Body>, ProstTwirpError> {
-        self.to_proto_raw().map(|v| v.to_hyper_raw())
+        self.to_proto_raw().and_then(|v| v.to_hyper_raw())
+    }
+}
+
+impl<T: Message + Default + serde::Serialize + 'static> ServiceRequest<T> {
+    /// Turn a protobuf service request into a byte-array service request encoded with the given codec
+    ///
+    /// This overrides the `Content-Type` header with the codec's media type.
+    pub fn to_codec_raw(&self, codec: &dyn Codec) -> Result<ServiceRequest<Vec<u8>>, ProstTwirpError> {
+        let mut req = self.clone_with_input(codec_encode(codec, &self.input)?);
+        req.headers.insert(CONTENT_TYPE, codec_content_type(codec)?);
+        Ok(req)
     }
 }
 
 /// A response with HTTP info and a serialized output object
-#[derive(Debug)]
+///
+/// Like requests, `Debug` output hides sensitive headers and truncates the output.
+#[derive(Clone)]
 pub struct ServiceResponse<T> {
     /// The HTTP version
     pub version: Version,
@@ -154,19 +735,70 @@ impl<T> ServiceResponse<T> {
     /// This automatically sets the `Content-Type` header as `application/protobuf`.
     pub fn new(output: T) -> ServiceResponse<T> { 
         let mut headers = HeaderMap::new();
-        headers.insert("Content-Type", application_proto());
+        headers.insert("Content-Type", Encoding::Protobuf.content_type());
         ServiceResponse {
             version: Version::default(),
-            headers: headers,
+            headers,
             status: StatusCode::OK,
             output
         }
     }
     
+    /// Create a service response like `new`, with another success status, e.g. `201 Created`
+    ///
+    /// Generated handlers and clients keep the status. Other statuses are reserved for errors, which
+    /// clients decode as such, so this gives an `internal` error if the status is not `2xx`.
+    pub fn with_status(output: T, status: StatusCode) -> Result<ServiceResponse<T>, ProstTwirpError> {
+        if !status.is_success() {
+            return Err(TwirpError::internal(&format!("ServiceResponse status {} is not a success status", status)).into());
+        }
+        Ok(ServiceResponse { status, ..ServiceResponse::new(output) })
+    }
+
     /// Copy this response with a different output value
     pub fn clone_with_output<U>(&self, output: U) -> ServiceResponse<U> {
         ServiceResponse { version: self.version, headers: self.headers.clone(), status: self.status, output }
     }
+
+    /// Set the given header, replacing any previous values, e.g. for `Cache-Control`
+    ///
+    /// Generated server handlers send these headers along with the encoded output.
+    pub fn with_header<K: IntoHeaderName>(mut self, name: K, value: HeaderValue) -> ServiceResponse<T> {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// The value of the given header, if present and valid UTF-8
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    /// All the values of the given header, skipping those that aren't valid UTF-8
+    pub fn header_all(&self, name: &str) -> Vec<&str> {
+        self.headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).collect()
+    }
+}
+
+impl<T: fmt::Debug> ServiceResponse<T> {
+    /// `Debug` output of the response hiding the values of the given headers
+    pub fn debug_with<'a>(&'a self, redaction: &'a RedactionConfig) -> impl fmt::Debug + 'a {
+        WithRedaction(self, redaction)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for ServiceResponse<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.fmt_redacted(f, None) }
+}
+
+impl<T: fmt::Debug> FmtRedacted for ServiceResponse<T> {
+    fn fmt_redacted(&self, f: &mut fmt::Formatter, redaction: Option<&RedactionConfig>) -> fmt::Result {
+        f.debug_struct("ServiceResponse")
+            .field("version", &self.version)
+            .field("headers", &RedactedHeaders(&self.headers, redaction))
+            .field("status", &self.status)
+            .field("output", &TruncatedBody(&self.output))
+            .finish()
+    }
 }
 
 impl<T: Message + Default + 'static> From<T> for ServiceResponse<T> {
@@ -176,24 +808,69 @@ impl<T: Message + Default + 'static> From<T> for ServiceResponse<T> {
 impl ServiceResponse<Vec<u8>> {
     /// Turn a hyper response to a boxed future of a byte-array service response
     pub fn from_hyper_raw(resp: Response<Body>) -> PTRes<Vec<u8>> {
+        ServiceResponse::from_hyper_raw_with_limit(resp, None)
+    }
+
+    /// Turn a hyper response to a boxed future of a byte-array service response, with an optional body size limit
+    ///
+    /// Bodies larger than the limit fail with a `resource_exhausted` error as soon as they exceed it,
+    /// or before reading them if their `Content-Length` does.
+    pub fn from_hyper_raw_with_limit(resp: Response<Body>, max_body_size: Option<usize>) -> PTRes<Vec<u8>> {
+        let too_large = || ProstTwirpError::from(TwirpError::resource_exhausted("Response body too large"));
+        let content_length = resp.headers().get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+        if let (Some(len), Some(max_body_size)) = (content_length, max_body_size) {
+            if len > max_body_size as u64 {
+                return Box::new(future::err(too_large()));
+            }
+        }
+
         let version = resp.version();
         let headers = resp.headers().clone();
         let status = resp.status();
-        Box::new(resp.into_body().concat2().map_err(ProstTwirpError::HyperError).map(move |body| {
-            ServiceResponse { version, headers, status, output: body.to_vec() }
+        Box::new(resp.into_body().map_err(ProstTwirpError::HyperError).fold(Vec::new(), move |mut body, chunk| {
+            if max_body_size.is_some_and(|max| body.len() + chunk.len() > max) {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        }).map(move |output| {
+            ServiceResponse { version, headers, status, output }
         }))
     }
 
     /// Turn a byte-array service response into a hyper response
-    pub fn to_hyper_raw(&self) -> Response<Body> {
+    pub fn to_hyper_raw(&self) -> Result<Response<Body>, ProstTwirpError> {
         let mut res = Response::builder()
             .status(self.status)
-            .body(Body::from(self.output.clone()))
-            .unwrap();
+            .version(self.version)
+            .body(Body::from(self.output.clone()))?;
 
         res.headers_mut().clone_from(&self.headers);
         res.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(self.output.len() as u64));
-        res
+        Ok(res)
+    }
+
+    /// Turn a byte-array service response into a hyper response streaming the body in chunks
+    ///
+    /// Unlike `to_hyper_raw`, the body is moved into the response rather than copied, and sent
+    /// without a `Content-Length`, so large bodies are only held in memory once.
+    pub fn into_hyper_streaming(self) -> Result<Response<Body>, ProstTwirpError> {
+        let chunks = stream::unfold(Bytes::from(self.output), |mut rest| {
+            if rest.is_empty() {
+                return None;
+            }
+            let chunk = rest.split_to(rest.len().min(STREAMING_CHUNK_SIZE));
+            Some(future::ok::<_, hyper::Error>((Chunk::from(chunk), rest)))
+        });
+        let mut res = Response::builder()
+            .status(self.status)
+            .version(self.version)
+            .body(Body::wrap_stream(chunks))?;
+
+        *res.headers_mut() = self.headers;
+        res.headers_mut().remove(CONTENT_LENGTH);
+        Ok(res)
     }
 
     /// Turn a byte-array service response into a `AfterBodyError`-wrapped version of the given error
@@ -205,17 +882,99 @@ impl ServiceResponse<Vec<u8>> {
     }
 
     /// Serialize the byte-array service response into a protobuf service response
+    ///
+    /// An empty successful body is the default message, e.g. for `google.protobuf.Empty`. This is
+    /// also how any message with only default fields is encoded, so it is valid for every type.
     pub fn to_proto<T: Message + Default + 'static>(&self) -> Result<ServiceResponse<T>, ProstTwirpError> {
         if self.status.is_success() {
+            if self.output.is_empty() {
+                return Ok(self.clone_with_output(T::default()));
+            }
             match T::decode(&self.output) {
                 Ok(v) => Ok(self.clone_with_output(v)),
                 Err(err) => Err(self.body_err(ProstTwirpError::ProstDecodeError(err)))
             }
         } else {
-            match TwirpError::from_json_bytes(self.status, &self.output) {
-                Ok(err) => Err(self.body_err(ProstTwirpError::TwirpError(err))),
-                Err(err) => Err(self.body_err(ProstTwirpError::JsonDecodeError(err)))
+            Err(self.error())
+        }
+    }
+
+    /// The Twirp error of an error response, or one derived from its status for non-Twirp bodies
+    fn error(&self) -> ProstTwirpError {
+        match TwirpError::from_json_bytes(self.status, &self.output) {
+            Ok(err) => self.body_err(ProstTwirpError::TwirpError(err)),
+            Err(_) => self.body_err(ProstTwirpError::TwirpError(TwirpError::from_intermediary(self.status, &self.output)))
+        }
+    }
+
+    /// Deserialize the byte-array service response from JSON into a protobuf service response
+    ///
+    /// Error responses are decoded like `to_proto` does.
+    pub fn to_proto_json<T: Message + Default + DeserializeOwned + 'static>(&self) -> Result<ServiceResponse<T>, ProstTwirpError> {
+        if !self.status.is_success() {
+            return self.to_proto();
+        }
+        if self.output.is_empty() {
+            return Ok(self.clone_with_output(T::default()));
+        }
+        match serde_json::from_slice(&self.output) {
+            Ok(v) => Ok(self.clone_with_output(v)),
+            Err(err) => Err(self.body_err(ProstTwirpError::JsonDecodeError(err)))
+        }
+    }
+
+    /// Decode the byte-array service response with the given codec into a protobuf service response
+    ///
+    /// Error responses are decoded like `to_proto` does.
+    pub fn to_proto_codec<T: Message + Default + DeserializeOwned + 'static>(&self, codec: &dyn Codec) -> Result<ServiceResponse<T>, ProstTwirpError> {
+        if !self.status.is_success() {
+            return self.to_proto();
+        }
+        match codec_decode(codec, &self.output) {
+            Ok(v) => Ok(self.clone_with_output(v)),
+            Err(err) => Err(self.body_err(err))
+        }
+    }
+
+    /// Deserialize the byte-array service response from protobuf or JSON, according to its `Content-Type`
+    ///
+    /// Successful responses of any other type fail with the `bad_content_type` or
+    /// `missing_content_type` error a server would reply with.
+    pub fn to_proto_auto<T: Message + Default + DeserializeOwned + 'static>(&self) -> Result<ServiceResponse<T>, ProstTwirpError> {
+        if !self.status.is_success() {
+            return self.to_proto();
+        }
+        match Encoding::from_headers(&self.headers, true) {
+            Ok(Encoding::Protobuf) => self.to_proto(),
+            Ok(Encoding::Json) => self.to_proto_json(),
+            Err(err) => Err(self.body_err(err.into())),
+        }
+    }
+
+    /// Decode the body according to its `Content-Encoding`, e.g. a gzipped body
+    pub fn decompress(self) -> Result<ServiceResponse<Vec<u8>>, ProstTwirpError> {
+        self.decompress_with_limit(None)
+    }
+
+    /// Decode the body like `decompress`, failing with a `resource_exhausted` error if it decodes
+    /// to more than `max_response_size` bytes
+    pub fn decompress_with_limit(mut self, max_response_size: Option<usize>) -> Result<ServiceResponse<Vec<u8>>, ProstTwirpError> {
+        self.output = decode_content(&mut self.headers, self.output, max_response_size, "Response body too large")?;
+        Ok(self)
+    }
+
+    /// Gzip the body if the given `Accept-Encoding` of the request allows it
+    ///
+    /// Without the `compression` feature, the response is always left as-is.
+    pub fn compress_for(self, accept_encoding: Option<&HeaderValue>) -> ServiceResponse<Vec<u8>> {
+        match accept_encoding {
+            #[cfg(feature = "compression")]
+            Some(accept_encoding) if accepts_gzip(accept_encoding) && !self.output.is_empty() => {
+                let mut resp = self;
+                resp.output = gzip_content(&mut resp.headers, &resp.output);
+                resp
             }
+            _ => self,
         }
     }
 }
@@ -238,71 +997,550 @@ impl<T: Message + Default + 'static> ServiceResponse<T> {
 
     /// Turn a protobuf service response into a hyper response
     pub fn to_hyper_proto(&self) -> Result<Response<Body>, ProstTwirpError> {
-        self.to_proto_raw().map(|v| v.to_hyper_raw())
+        self.to_proto_raw().and_then(|v| v.to_hyper_raw())
     }
-}
 
-/// A JSON-serializable Twirp error
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-pub struct TwirpError {
-    #[serde(skip)]
-    pub status: StatusCode,
-    pub code: String,
-    pub msg: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub meta: Option<serde_json::Value>,
+    /// Turn a protobuf service response into a hyper response with a chunked body, like `into_hyper_streaming`
+    pub fn to_hyper_proto_streaming(&self) -> Result<Response<Body>, ProstTwirpError> {
+        self.to_proto_raw().and_then(|v| v.into_hyper_streaming())
+    }
 }
 
-impl TwirpError {
-    /// Create a Twirp error with no meta
-    pub fn new(status: StatusCode, code: &str, msg: &str) -> TwirpError {
-        TwirpError::new_meta(status, code, msg, None)
+impl<T: Message + Default + serde::Serialize + 'static> ServiceResponse<T> {
+    /// Turn a protobuf service response into a byte-array service response encoded as JSON
+    ///
+    /// This overrides the `Content-Type` header as `application/json`.
+    pub fn to_json_raw(&self) -> Result<ServiceResponse<Vec<u8>>, ProstTwirpError> {
+        match serde_json::to_vec(&self.output) {
+            Ok(body) => {
+                let mut resp = self.clone_with_output(body);
+                resp.headers.insert(CONTENT_TYPE, Encoding::Json.content_type());
+                Ok(resp)
+            }
+            Err(err) => Err(ProstTwirpError::JsonEncodeError(err))
+        }
     }
 
-    /// Create a Twirp error with optional meta
-    pub fn new_meta(status: StatusCode, error_type: &str, msg: &str, meta: Option<serde_json::Value>) -> TwirpError {
-        TwirpError { status, code: error_type.to_string(), msg: msg.to_string(), meta }
+    /// Turn a protobuf service response into a JSON hyper response
+    pub fn to_hyper_json(&self) -> Result<Response<Body>, ProstTwirpError> {
+        self.to_json_raw().and_then(|v| v.to_hyper_raw())
+    }
+
+    /// Turn a protobuf service response into a byte-array service response encoded with the given codec
+    ///
+    /// This overrides the `Content-Type` header with the codec's media type.
+    pub fn to_codec_raw(&self, codec: &dyn Codec) -> Result<ServiceResponse<Vec<u8>>, ProstTwirpError> {
+        let mut resp = self.clone_with_output(codec_encode(codec, &self.output)?);
+        resp.headers.insert(CONTENT_TYPE, codec_content_type(codec)?);
+        Ok(resp)
+    }
+}
+
+/// The `Content-Type` header value for the given codec
+fn codec_content_type(codec: &dyn Codec) -> Result<HeaderValue, ProstTwirpError> {
+    HeaderValue::from_str(codec.media_type()).map_err(|_| TwirpError::internal(
+        &format!("Invalid codec media type {:?}", codec.media_type())).into())
+}
+
+fn canonical_status(code: &str) -> Option<StatusCode> {
+    TwirpCode::canonical(code).map(TwirpCode::default_status)
+}
+
+/// A canonical Twirp error code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TwirpCode {
+    /// The operation was cancelled, `canceled`
+    Canceled,
+    /// An unknown error, `unknown`; also used for non-canonical codes
+    Unknown,
+    /// The client specified an invalid argument, `invalid_argument`
+    InvalidArgument,
+    /// The client sent a message that could not be decoded, `malformed`
+    Malformed,
+    /// The operation expired before completion, `deadline_exceeded`
+    DeadlineExceeded,
+    /// Some requested entity was not found, `not_found`
+    NotFound,
+    /// The requested URL path wasn't routable to a method, `bad_route`
+    BadRoute,
+    /// An entity the client tried to create already exists, `already_exists`
+    AlreadyExists,
+    /// The caller can't execute the operation, `permission_denied`
+    PermissionDenied,
+    /// The request has no valid credentials, `unauthenticated`
+    Unauthenticated,
+    /// Some resource has been exhausted, `resource_exhausted`
+    ResourceExhausted,
+    /// The system isn't in a state required for the operation, `failed_precondition`
+    FailedPrecondition,
+    /// The operation was aborted, e.g. by a concurrency issue, `aborted`
+    Aborted,
+    /// The operation was attempted past the valid range, `out_of_range`
+    OutOfRange,
+    /// The operation isn't implemented or supported, `unimplemented`
+    Unimplemented,
+    /// Some invariant expected by the system has been broken, `internal`
+    Internal,
+    /// The service is currently unavailable, `unavailable`
+    Unavailable,
+    /// Unrecoverable data loss or corruption, `data_loss`
+    DataLoss,
+}
+
+impl TwirpCode {
+    /// The canonical code of the given error
+    ///
+    /// Twirp errors with non-canonical codes are `Unknown`. Other errors are categorized like the
+    /// Twirp error they would be: timeouts are `DeadlineExceeded`, hyper and other transport errors
+    /// (e.g. failures to connect) are `Unavailable`, and anything else is `Internal`.
+    pub fn classify(err: &ProstTwirpError) -> TwirpCode {
+        match *err {
+            ProstTwirpError::TwirpError(ref err) => TwirpCode::parse(&err.code),
+            ProstTwirpError::AfterBodyError { ref err, .. } => TwirpCode::classify(err),
+            ProstTwirpError::Timeout => TwirpCode::DeadlineExceeded,
+            ProstTwirpError::HyperError(_) | ProstTwirpError::TransportError(_) => TwirpCode::Unavailable,
+            _ => TwirpCode::Internal,
+        }
+    }
+
+    /// Every canonical code, in the order they are listed in the spec
+    pub const ALL: [TwirpCode; 18] = [
+        TwirpCode::Canceled,
+        TwirpCode::Unknown,
+        TwirpCode::InvalidArgument,
+        TwirpCode::Malformed,
+        TwirpCode::DeadlineExceeded,
+        TwirpCode::NotFound,
+        TwirpCode::BadRoute,
+        TwirpCode::AlreadyExists,
+        TwirpCode::PermissionDenied,
+        TwirpCode::Unauthenticated,
+        TwirpCode::ResourceExhausted,
+        TwirpCode::FailedPrecondition,
+        TwirpCode::Aborted,
+        TwirpCode::OutOfRange,
+        TwirpCode::Unimplemented,
+        TwirpCode::Internal,
+        TwirpCode::Unavailable,
+        TwirpCode::DataLoss,
+    ];
+
+    /// The code as sent on the wire, e.g. `"invalid_argument"`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TwirpCode::Canceled => "canceled",
+            TwirpCode::Unknown => "unknown",
+            TwirpCode::InvalidArgument => "invalid_argument",
+            TwirpCode::Malformed => "malformed",
+            TwirpCode::DeadlineExceeded => "deadline_exceeded",
+            TwirpCode::NotFound => "not_found",
+            TwirpCode::BadRoute => "bad_route",
+            TwirpCode::AlreadyExists => "already_exists",
+            TwirpCode::PermissionDenied => "permission_denied",
+            TwirpCode::Unauthenticated => "unauthenticated",
+            TwirpCode::ResourceExhausted => "resource_exhausted",
+            TwirpCode::FailedPrecondition => "failed_precondition",
+            TwirpCode::Aborted => "aborted",
+            TwirpCode::OutOfRange => "out_of_range",
+            TwirpCode::Unimplemented => "unimplemented",
+            TwirpCode::Internal => "internal",
+            TwirpCode::Unavailable => "unavailable",
+            TwirpCode::DataLoss => "data_loss",
+        }
+    }
+
+    /// The HTTP status the spec assigns to this code
+    pub fn default_status(self) -> StatusCode {
+        match self {
+            TwirpCode::Canceled => StatusCode::REQUEST_TIMEOUT,
+            TwirpCode::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
+            TwirpCode::InvalidArgument => StatusCode::BAD_REQUEST,
+            TwirpCode::Malformed => StatusCode::BAD_REQUEST,
+            TwirpCode::DeadlineExceeded => StatusCode::REQUEST_TIMEOUT,
+            TwirpCode::NotFound => StatusCode::NOT_FOUND,
+            TwirpCode::BadRoute => StatusCode::NOT_FOUND,
+            TwirpCode::AlreadyExists => StatusCode::CONFLICT,
+            TwirpCode::PermissionDenied => StatusCode::FORBIDDEN,
+            TwirpCode::Unauthenticated => StatusCode::UNAUTHORIZED,
+            TwirpCode::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+            TwirpCode::FailedPrecondition => StatusCode::PRECONDITION_FAILED,
+            TwirpCode::Aborted => StatusCode::CONFLICT,
+            TwirpCode::OutOfRange => StatusCode::BAD_REQUEST,
+            TwirpCode::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+            TwirpCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            TwirpCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            TwirpCode::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn parse(code: &str) -> TwirpCode {
+        TwirpCode::canonical(code).unwrap_or(TwirpCode::Unknown)
+    }
+
+    fn canonical(code: &str) -> Option<TwirpCode> {
+        TwirpCode::ALL.iter().cloned().find(|c| c.as_str() == code)
+    }
+}
+
+impl std::str::FromStr for TwirpCode {
+    type Err = std::convert::Infallible;
+
+    /// Parse a wire code; non-canonical codes are `Unknown`
+    fn from_str(code: &str) -> Result<TwirpCode, Self::Err> {
+        Ok(TwirpCode::parse(code))
+    }
+}
+
+impl fmt::Display for TwirpCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A JSON-serializable Twirp error
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TwirpError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    pub code: String,
+    pub msg: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<serde_json::Value>,
+}
+
+impl TwirpError {
+    /// Create a Twirp error with no meta
+    pub fn new(status: StatusCode, code: &str, msg: &str) -> TwirpError {
+        TwirpError::new_meta(status, code, msg, None)
+    }
+
+    /// Create a Twirp error with optional meta
+    pub fn new_meta(status: StatusCode, error_type: &str, msg: &str, meta: Option<serde_json::Value>) -> TwirpError {
+        TwirpError { status, code: error_type.to_string(), msg: msg.to_string(), meta }
+    }
+
+    /// Create a Twirp error with no meta, deriving the status from the code
+    pub fn new_code(code: &str, msg: &str) -> TwirpError {
+        TwirpError::new(TwirpError::code_to_status(code), code, msg)
+    }
+
+    /// The HTTP status for the given canonical Twirp code
+    ///
+    /// Unknown codes map to `500 Internal Server Error`.
+    pub fn code_to_status(code: &str) -> StatusCode {
+        canonical_status(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// The code as a `TwirpCode`, with non-canonical codes as `TwirpCode::Unknown`
+    pub fn code_enum(&self) -> TwirpCode {
+        TwirpCode::parse(&self.code)
+    }
+
+    /// Add an entry to the meta, keeping it the string-to-string map required by the spec
+    ///
+    /// Meta that isn't a JSON object is replaced.
+    pub fn with_meta_entry(mut self, key: &str, value: &str) -> TwirpError {
+        let mut meta = match self.meta.take() {
+            Some(serde_json::Value::Object(meta)) => meta,
+            _ => serde_json::Map::new(),
+        };
+        meta.insert(key.to_string(), serde_json::Value::String(value.to_string()));
+        self.meta = Some(serde_json::Value::Object(meta));
+        self
+    }
+
+    /// The meta as a string-to-string map, or `None` if there is no meta or it doesn't follow the spec
+    pub fn meta_map(&self) -> Option<BTreeMap<String, String>> {
+        match self.meta {
+            Some(serde_json::Value::Object(ref meta)) => meta.iter()
+                .map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+                .collect(),
+            _ => None,
+        }
+    }
+
+    /// The given meta entry, if present and a string
+    pub fn meta_entry(&self, key: &str) -> Option<&str> {
+        self.meta.as_ref().and_then(|meta| meta.get(key)).and_then(|v| v.as_str())
+    }
+
+    /// The invalid field of an `invalid_argument` error, from the conventional `argument` meta entry
+    pub fn argument(&self) -> Option<&str> {
+        self.meta_entry("argument")
+    }
+
+    /// Every field violation added with `invalid_argument_field` or `with_field_violation`, by field
+    pub fn field_violations(&self) -> BTreeMap<String, String> {
+        self.meta_map().unwrap_or_default().into_iter()
+            .filter_map(|(k, v)| k.strip_prefix("argument.").map(|field| (field.to_string(), v)))
+            .collect()
+    }
+
+    /// Whether the status of this error is the one its code is sent with per the spec
+    ///
+    /// For errors decoded from responses, this tells whether the remote server followed the spec.
+    /// Custom codes have no expected status, so they always match, and `bad_route` also matches `405`.
+    pub fn status_matches_code(&self) -> bool {
+        self.status == self.wire_status()
+    }
+
+    /// The status this error is sent with; canonical codes always use their spec status
+    ///
+    /// The only exception is `bad_route`, which may also be sent as `405 Method Not Allowed` for
+    /// known routes requested with the wrong method.
+    fn wire_status(&self) -> StatusCode {
+        if self.code == "bad_route" && self.status == StatusCode::METHOD_NOT_ALLOWED {
+            return self.status;
+        }
+        canonical_status(&self.code).unwrap_or(self.status)
+    }
+
+    /// Create a `canceled` error
+    pub fn canceled(msg: &str) -> TwirpError {
+        TwirpError::new_code("canceled", msg)
+    }
+
+    /// Create a `unknown` error
+    pub fn unknown(msg: &str) -> TwirpError {
+        TwirpError::new_code("unknown", msg)
+    }
+
+    /// Create a `invalid_argument` error
+    pub fn invalid_argument(msg: &str) -> TwirpError {
+        TwirpError::new_code("invalid_argument", msg)
+    }
+
+    /// Create an `invalid_argument` error for the given field, e.g. `("size", "must be positive")`
+    ///
+    /// The field is the `argument` meta entry, like in other Twirp implementations, and the message
+    /// is the field followed by the reason. Other violations can be added with `with_field_violation`.
+    pub fn invalid_argument_field(field: &str, reason: &str) -> TwirpError {
+        TwirpError::invalid_argument(&format!("{} {}", field, reason)).with_field_violation(field, reason)
+    }
+
+    /// Add a field violation, as an `argument.{field}` meta entry with the reason
+    ///
+    /// The first violation also sets the `argument` meta entry.
+    pub fn with_field_violation(self, field: &str, reason: &str) -> TwirpError {
+        let err = if self.argument().is_none() { self.with_meta_entry("argument", field) } else { self };
+        err.with_meta_entry(&format!("argument.{}", field), reason)
+    }
+
+    /// Create a `malformed` error
+    pub fn malformed(msg: &str) -> TwirpError {
+        TwirpError::new_code("malformed", msg)
+    }
+
+    /// Create a `deadline_exceeded` error
+    pub fn deadline_exceeded(msg: &str) -> TwirpError {
+        TwirpError::new_code("deadline_exceeded", msg)
+    }
+
+    /// Create a `not_found` error
+    pub fn not_found(msg: &str) -> TwirpError {
+        TwirpError::new_code("not_found", msg)
+    }
+
+    /// Create a `bad_route` error
+    pub fn bad_route(msg: &str) -> TwirpError {
+        TwirpError::new_code("bad_route", msg)
+    }
+
+    /// Create a `already_exists` error
+    pub fn already_exists(msg: &str) -> TwirpError {
+        TwirpError::new_code("already_exists", msg)
+    }
+
+    /// Create a `permission_denied` error
+    pub fn permission_denied(msg: &str) -> TwirpError {
+        TwirpError::new_code("permission_denied", msg)
+    }
+
+    /// Create a `unauthenticated` error
+    pub fn unauthenticated(msg: &str) -> TwirpError {
+        TwirpError::new_code("unauthenticated", msg)
+    }
+
+    /// Create a `resource_exhausted` error
+    pub fn resource_exhausted(msg: &str) -> TwirpError {
+        TwirpError::new_code("resource_exhausted", msg)
+    }
+
+    /// Create a `failed_precondition` error
+    pub fn failed_precondition(msg: &str) -> TwirpError {
+        TwirpError::new_code("failed_precondition", msg)
+    }
+
+    /// Create a `aborted` error
+    pub fn aborted(msg: &str) -> TwirpError {
+        TwirpError::new_code("aborted", msg)
+    }
+
+    /// Create a `out_of_range` error
+    pub fn out_of_range(msg: &str) -> TwirpError {
+        TwirpError::new_code("out_of_range", msg)
+    }
+
+    /// Create a `unimplemented` error
+    pub fn unimplemented(msg: &str) -> TwirpError {
+        TwirpError::new_code("unimplemented", msg)
+    }
+
+    /// Create a `internal` error
+    pub fn internal(msg: &str) -> TwirpError {
+        TwirpError::new_code("internal", msg)
+    }
+
+    /// Create an `internal` error with the message of the given error, e.g. `map_err(TwirpError::internal_from)`
+    ///
+    /// Only the error's own message is sent; `internal_from_with_sources` also sends the messages
+    /// of its sources, which may reveal more about the server.
+    pub fn internal_from<E: Error>(err: E) -> TwirpError {
+        TwirpError::internal(&err.to_string())
+    }
+
+    /// Create an `internal` error like `internal_from`, with the messages of the error's sources
+    ///
+    /// The sources are in the `causes` meta entry, outermost first and separated by `: `.
+    pub fn internal_from_with_sources<E: Error>(err: E) -> TwirpError {
+        let mut causes = Vec::new();
+        let mut source = err.source();
+        while let Some(cause) = source {
+            causes.push(cause.to_string());
+            source = cause.source();
+        }
+        let internal = TwirpError::internal_from(err);
+        if causes.is_empty() {
+            return internal;
+        }
+        internal.with_meta_entry("causes", &causes.join(": "))
+    }
+
+    /// Create a `unavailable` error
+    pub fn unavailable(msg: &str) -> TwirpError {
+        TwirpError::new_code("unavailable", msg)
+    }
+
+    /// Create a `data_loss` error
+    pub fn data_loss(msg: &str) -> TwirpError {
+        TwirpError::new_code("data_loss", msg)
     }
 
     /// Create a byte-array service response for this error and the given status code
     pub fn to_resp_raw(&self) -> ServiceResponse<Vec<u8>> {
         let output = self.to_json_bytes().unwrap_or_else(|_| "{}".as_bytes().to_vec());
         let mut headers = HeaderMap::new();
-        headers.insert(CONTENT_TYPE, application_json());
+        headers.insert(CONTENT_TYPE, Encoding::Json.content_type());
         headers.insert(CONTENT_LENGTH, HeaderValue::from(output.len() as u64));
         ServiceResponse {
             version: Version::default(),
-            headers: headers,
-            status: self.status,
+            headers,
+            status: self.wire_status(),
             output
         }
     }
 
     /// Create a hyper response for this error and the given status code
-    pub fn to_hyper_resp(&self) -> Response<Body> {
+    pub fn to_hyper_resp(&self) -> Result<Response<Body>, ProstTwirpError> {
         let body = self.to_json_bytes().unwrap_or_else(|_| "{}".as_bytes().to_vec());
-        Response::builder().
-            status(self.status).
-            header(CONTENT_TYPE, application_json()).
+        Ok(Response::builder().
+            status(self.wire_status()).
+            header(CONTENT_TYPE, Encoding::Json.content_type()).
             header(CONTENT_LENGTH, body.len() as u64).
-            body(Body::from(body)).unwrap()
+            body(Body::from(body))?)
     }
 
     /// Create error from byte array
+    ///
+    /// As the Twirp spec requires, the body must have a `code` and a `msg`, and the code must not be
+    /// empty.
     pub fn from_json_bytes(status: StatusCode, json: &[u8]) -> serde_json::Result<TwirpError> {
-        serde_json::from_slice(json).map(|err| TwirpError{ status, ..err })
+        let err: TwirpError = serde_json::from_slice(json)?;
+        if err.code.trim().is_empty() {
+            return Err(serde::de::Error::custom("empty Twirp error code"));
+        }
+        Ok(TwirpError { status, ..err })
+    }
+
+    /// Create an error for a non-Twirp error response, e.g. an HTML page from a load balancer
+    ///
+    /// As with other Twirp implementations, the code is derived from the status, and the message
+    /// includes the start of the body.
+    pub fn from_intermediary(status: StatusCode, body: &[u8]) -> TwirpError {
+        const MAX_SNIPPET: usize = 256;
+
+        let code = match status.as_u16() {
+            300..=399 => "internal",
+            400 => "internal",
+            401 => "unauthenticated",
+            403 => "permission_denied",
+            404 => "bad_route",
+            429 | 502 | 503 | 504 => "unavailable",
+            _ => "unknown",
+        };
+        let snippet = String::from_utf8_lossy(&body[..body.len().min(MAX_SNIPPET)]);
+        let ellipsis = if body.len() > MAX_SNIPPET { "..." } else { "" };
+        let msg = format!("Error from intermediary with HTTP status {}: {}{}", status, snippet.trim(), ellipsis);
+        TwirpError::new(status, code, &msg)
+            .with_meta_entry("http_error_from_intermediary", "true")
+            .with_meta_entry("status_code", status.as_str())
     }
 
     /// Create byte array from error
     pub fn to_json_bytes(&self) -> serde_json::Result<Vec<u8>> {
         serde_json::to_vec(&self)
     }
+
+    /// Create a pretty-printed byte array from error, e.g. for human-facing debug endpoints
+    ///
+    /// Responses always use the compact `to_json_bytes`.
+    pub fn to_json_bytes_pretty(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec_pretty(&self)
+    }
+
+    /// Create byte array from error with the given `serde_json` formatter, e.g. with custom indentation
+    pub fn to_json_bytes_with<F: serde_json::ser::Formatter>(&self, formatter: F) -> serde_json::Result<Vec<u8>> {
+        use serde::Serialize;
+
+        let mut buf = Vec::new();
+        self.serialize(&mut serde_json::Serializer::with_formatter(&mut buf, formatter))?;
+        Ok(buf)
+    }
+}
+
+impl fmt::Display for TwirpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.msg)
+    }
 }
 
+impl Error for TwirpError {}
+
 impl From<TwirpError> for ProstTwirpError {
     fn from(v: TwirpError) -> ProstTwirpError { ProstTwirpError::TwirpError(v) }
 }
 
+impl From<hyper::http::Error> for ProstTwirpError {
+    fn from(v: hyper::http::Error) -> ProstTwirpError { ProstTwirpError::HttpBuilderError(v) }
+}
+
+/// JSON errors are assumed to come from decoding; wrap encoding errors in `JsonEncodeError` explicitly
+impl From<serde_json::Error> for ProstTwirpError {
+    fn from(v: serde_json::Error) -> ProstTwirpError { ProstTwirpError::JsonDecodeError(v) }
+}
+
+impl From<DecodeError> for ProstTwirpError {
+    fn from(v: DecodeError) -> ProstTwirpError { ProstTwirpError::ProstDecodeError(v) }
+}
+
+impl From<EncodeError> for ProstTwirpError {
+    fn from(v: EncodeError) -> ProstTwirpError { ProstTwirpError::ProstEncodeError(v) }
+}
+
+impl From<hyper::Error> for ProstTwirpError {
+    fn from(v: hyper::Error) -> ProstTwirpError { ProstTwirpError::HyperError(v) }
+}
+
 /// An error that can occur during a call to a Twirp service
 #[derive(Debug)]
 pub enum ProstTwirpError {
@@ -310,12 +1548,22 @@ pub enum ProstTwirpError {
     TwirpError(TwirpError),
     /// An error when trying to decode JSON into an error or object
     JsonDecodeError(serde_json::Error),
+    /// An error when trying to encode an object as JSON
+    JsonEncodeError(serde_json::Error),
     /// An error when trying to encode a protobuf object
     ProstEncodeError(EncodeError),
     /// An error when trying to decode a protobuf object
     ProstDecodeError(DecodeError),
     /// A generic hyper error
     HyperError(hyper::Error),
+    /// A client request URI that could not be parsed
+    InvalidUri(String),
+    /// An error when trying to build a hyper request or response
+    HttpBuilderError(hyper::http::Error),
+    /// A client request that did not complete within its timeout
+    Timeout,
+    /// A failure of another HTTP transport than hyper 0.12, e.g. that of the `async_client`
+    TransportError(Box<dyn Error + Send + Sync>),
 
     /// A wrapper for any of the other `ProstTwirpError`s that also includes request/response info
     AfterBodyError {
@@ -335,6 +1583,20 @@ pub enum ProstTwirpError {
 }
 
 impl ProstTwirpError {
+    /// Read a non-2xx hyper response into the Twirp error it carries, e.g. in a bespoke client
+    ///
+    /// The body is decompressed and decoded like `ServiceResponse::to_proto` does, including
+    /// non-Twirp bodies from intermediaries. The future always fails, so it can be returned as any
+    /// `PTRes`; successful responses fail with an `internal` error.
+    pub fn from_hyper_error_response<T: Send + 'static>(resp: Response<Body>) -> Box<dyn Future<Item=T, Error=ProstTwirpError> + Send> {
+        Box::new(ServiceResponse::from_hyper_raw(resp).and_then(|resp| resp.decompress()).then(|res| Err(match res {
+            Ok(ref resp) if resp.status.is_success() => resp.body_err(TwirpError::internal(
+                &format!("Expected an error response, got status {}", resp.status)).into()),
+            Ok(resp) => resp.error(),
+            Err(err) => err,
+        })))
+    }
+
     /// This same error, or the underlying error if it is an `AfterBodyError`
     pub fn root_err(self) -> ProstTwirpError {
         match self {
@@ -343,21 +1605,313 @@ impl ProstTwirpError {
         }
     }
 
+    /// The Twirp error, including its meta, if this is or wraps one
+    pub fn twirp_error(&self) -> Option<&TwirpError> {
+        match *self {
+            ProstTwirpError::TwirpError(ref err) => Some(err),
+            ProstTwirpError::AfterBodyError { ref err, .. } => err.twirp_error(),
+            _ => None
+        }
+    }
+
+    /// The raw body of the request or response the error happened after, if this is an `AfterBodyError`
+    ///
+    /// This is e.g. the successful response body that failed to decode.
+    pub fn after_body(&self) -> Option<&[u8]> {
+        match *self {
+            ProstTwirpError::AfterBodyError { ref body, .. } => Some(body),
+            _ => None
+        }
+    }
+
+    /// Whether this is or wraps a Twirp error with the given code
+    pub fn is_twirp_code(&self, code: &str) -> bool {
+        self.twirp_code() == Some(code)
+    }
+
+    /// The code of the Twirp error, if this is or wraps one
+    pub fn twirp_code(&self) -> Option<&str> {
+        self.twirp_error().map(|err| err.code.as_str())
+    }
+
+    /// The HTTP status of the Twirp error, if this is or wraps one
+    pub fn http_status(&self) -> Option<StatusCode> {
+        self.twirp_error().map(|err| err.status)
+    }
+
+    /// Turn this error into the hyper response a server replies with
+    ///
+    /// Hyper errors are propagated as-is, since there is no response to reply with.
     pub fn to_hyper_resp(self) -> Result<Response<Body>, hyper::Error> {
-        match self.root_err() {
-            ProstTwirpError::ProstDecodeError(_) =>
-                Ok(TwirpError::new(StatusCode::BAD_REQUEST, "protobuf_decode_err", "Invalid protobuf body").
-                    to_hyper_resp()),
+        Ok(error_resp(self.into_twirp_error()?.to_hyper_resp()))
+    }
+
+    /// Turn this error into a hyper response like `to_hyper_resp`, with the status overrides of the given config
+    pub fn to_hyper_resp_with_config(self, config: &ServerConfig) -> Result<Response<Body>, hyper::Error> {
+        let err = self.into_twirp_error_with(config.decode_error_details)?;
+        let mut resp = err.to_resp_raw();
+        resp.status = config.error_status(&err);
+        Ok(error_resp(resp.to_hyper_raw()))
+    }
+
+    /// The HTTP status of the response `to_hyper_resp` replies with for this error
+    ///
+    /// This is cheap, e.g. for tagging metrics; hyper errors, which have no response, are `500`.
+    pub fn status_code(&self) -> StatusCode {
+        match *self {
+            ProstTwirpError::AfterBodyError { ref err, .. } => err.status_code(),
+            ProstTwirpError::ProstDecodeError(_) => StatusCode::BAD_REQUEST,
+            ProstTwirpError::JsonDecodeError(_) => TwirpCode::Malformed.default_status(),
+            ProstTwirpError::Timeout => TwirpCode::DeadlineExceeded.default_status(),
+            ProstTwirpError::TwirpError(ref err) => err.wire_status(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Whether this is or wraps an error decoding a protobuf or JSON body
+    #[cfg(feature = "tracing")]
+    fn is_decode_error(&self) -> bool {
+        match *self {
+            ProstTwirpError::AfterBodyError { ref err, .. } => err.is_decode_error(),
+            ProstTwirpError::ProstDecodeError(_) | ProstTwirpError::JsonDecodeError(_) => true,
+            _ => false
+        }
+    }
+
+    /// The Twirp error a server replies with for this error
+    fn into_twirp_error(self) -> Result<TwirpError, hyper::Error> {
+        self.into_twirp_error_with(false)
+    }
+
+    /// The Twirp error a server replies with for this error, with the decoder's message for decode
+    /// errors if `details` is set
+    fn into_twirp_error_with(self, details: bool) -> Result<TwirpError, hyper::Error> {
+        let msg = |generic: &str, err: &dyn fmt::Display| if details {
+            format!("{}: {}", generic, err)
+        } else {
+            generic.to_string()
+        };
+        Ok(match self.root_err() {
+            ProstTwirpError::ProstDecodeError(err) =>
+                TwirpError::new(StatusCode::BAD_REQUEST, "protobuf_decode_err", &msg("Invalid protobuf body", &err)),
+            ProstTwirpError::JsonDecodeError(err) =>
+                TwirpError::malformed(&msg("Invalid JSON body", &err)),
+            ProstTwirpError::Timeout =>
+                TwirpError::deadline_exceeded("Request timed out"),
             ProstTwirpError::TwirpError(err) =>
-                Ok(err.to_hyper_resp()),
+                err,
             // Just propagate hyper errors
             ProstTwirpError::HyperError(err) =>
-                Err(err),
+                return Err(err),
             _ =>
-                Ok(TwirpError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_err", "Internal Error").
-                    to_hyper_resp()),
+                TwirpError::internal("Internal Error"),
+        })
+    }
+}
+
+/// The built error response, or a bare 500 if even the error response can't be built
+fn error_resp(resp: Result<Response<Body>, ProstTwirpError>) -> Response<Body> {
+    resp.unwrap_or_else(|_| {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        resp
+    })
+}
+
+/// Errors compare equal if they are the same variant with equal contents
+///
+/// Errors without a comparable representation compare by their messages, except for hyper and
+/// other transport errors which never compare equal.
+impl PartialEq for ProstTwirpError {
+    fn eq(&self, other: &ProstTwirpError) -> bool {
+        use self::ProstTwirpError::*;
+        match (self, other) {
+            (TwirpError(a), TwirpError(b)) => a == b,
+            (JsonDecodeError(a), JsonDecodeError(b)) | (JsonEncodeError(a), JsonEncodeError(b)) =>
+                a.to_string() == b.to_string(),
+            (ProstEncodeError(a), ProstEncodeError(b)) => a.to_string() == b.to_string(),
+            (ProstDecodeError(a), ProstDecodeError(b)) => a.to_string() == b.to_string(),
+            (InvalidUri(a), InvalidUri(b)) => a == b,
+            (HttpBuilderError(a), HttpBuilderError(b)) => a.to_string() == b.to_string(),
+            (Timeout, Timeout) => true,
+            (AfterBodyError { body, method, version, headers, status, err },
+             AfterBodyError { body: b_body, method: b_method, version: b_version, headers: b_headers, status: b_status, err: b_err }) =>
+                body == b_body && method == b_method && version == b_version && headers == b_headers &&
+                    status == b_status && err == b_err,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ProstTwirpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProstTwirpError::TwirpError(err) => write!(f, "twirp error: {}", err),
+            ProstTwirpError::JsonDecodeError(err) => write!(f, "JSON decode error: {}", err),
+            ProstTwirpError::JsonEncodeError(err) => write!(f, "JSON encode error: {}", err),
+            ProstTwirpError::ProstEncodeError(err) => write!(f, "protobuf encode error: {}", err),
+            ProstTwirpError::ProstDecodeError(err) => write!(f, "protobuf decode error: {}", err),
+            ProstTwirpError::HyperError(err) => write!(f, "hyper error: {}", err),
+            ProstTwirpError::InvalidUri(uri) => write!(f, "invalid URI: {}", uri),
+            ProstTwirpError::HttpBuilderError(err) => write!(f, "HTTP builder error: {}", err),
+            ProstTwirpError::Timeout => write!(f, "request timed out"),
+            ProstTwirpError::TransportError(err) => write!(f, "transport error: {}", err),
+            ProstTwirpError::AfterBodyError { err, .. } => err.fmt(f),
+        }
+    }
+}
+
+impl Error for ProstTwirpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProstTwirpError::TwirpError(_) => None,
+            ProstTwirpError::JsonDecodeError(err) => Some(err),
+            ProstTwirpError::JsonEncodeError(err) => Some(err),
+            ProstTwirpError::ProstEncodeError(err) => Some(err),
+            ProstTwirpError::ProstDecodeError(err) => Some(err),
+            ProstTwirpError::HyperError(err) => Some(err),
+            ProstTwirpError::InvalidUri(_) => None,
+            ProstTwirpError::HttpBuilderError(err) => Some(err),
+            ProstTwirpError::Timeout => None,
+            ProstTwirpError::TransportError(err) => Some(err.as_ref()),
+            ProstTwirpError::AfterBodyError { err, .. } => Some(err.as_ref()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod encoding_tests {
+    use super::*;
+
+    #[test]
+    fn content_type_parsing() {
+        let parse = |ct| Encoding::from_content_type(&HeaderValue::from_static(ct));
+        assert_eq!(parse("application/protobuf"), Some(Encoding::Protobuf));
+        assert_eq!(parse("application/json"), Some(Encoding::Json));
+        assert_eq!(parse("Application/JSON; charset=utf-8"), Some(Encoding::Json));
+        assert_eq!(parse("text/html"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn request_content_type() {
+        let check = |ct: Option<&'static str>, allow_json| {
+            let mut headers = HeaderMap::new();
+            if let Some(ct) = ct {
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static(ct));
+            }
+            Encoding::from_headers(&headers, allow_json).map_err(|err| (err.status, err.code, err.msg))
+        };
+        assert_eq!(check(Some("application/protobuf"), false), Ok(Encoding::Protobuf));
+        assert_eq!(check(Some("application/json"), true), Ok(Encoding::Json));
+
+        let missing = Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, "missing_content_type".to_string(), "Content type is missing".to_string()));
+        assert_eq!(check(None, true), missing);
+        assert_eq!(check(Some(""), true), missing);
+        assert_eq!(check(Some(" "), false), missing);
+
+        assert_eq!(check(Some("application/json"), false), Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, "bad_content_type".to_string(),
+            "Unsupported content type application/json, must be application/protobuf".to_string())));
+        assert_eq!(check(Some("text/html"), true).unwrap_err().2,
+            "Unsupported content type text/html, must be application/protobuf or application/json");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, Encoding::Json.content_type());
+        assert_eq!(Encoding::from_headers_accepting(&headers, &[Encoding::Json]), Ok(Encoding::Json));
+        headers.insert(CONTENT_TYPE, Encoding::Protobuf.content_type());
+        let err = Encoding::from_headers_accepting(&headers, &[Encoding::Json]).unwrap_err();
+        assert_eq!((err.status, err.msg.as_str()), (StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported content type application/protobuf, must be application/json"));
+    }
+
+    #[test]
+    fn accept_negotiation() {
+        let negotiate = |accept: Option<&'static str>, request_encoding| {
+            let mut headers = HeaderMap::new();
+            if let Some(accept) = accept {
+                headers.insert(hyper::header::ACCEPT, HeaderValue::from_static(accept));
+            }
+            Encoding::negotiate(&headers, request_encoding)
+        };
+        assert_eq!(negotiate(None, Encoding::Protobuf), Encoding::Protobuf);
+        assert_eq!(negotiate(Some("application/json"), Encoding::Protobuf), Encoding::Json);
+        assert_eq!(negotiate(Some("application/protobuf"), Encoding::Json), Encoding::Protobuf);
+        assert_eq!(negotiate(Some("*/*"), Encoding::Json), Encoding::Json);
+        assert_eq!(negotiate(Some("text/html, */*;q=0.8"), Encoding::Protobuf), Encoding::Protobuf);
+        assert_eq!(negotiate(Some("application/json;q=oops"), Encoding::Protobuf), Encoding::Protobuf);
+        assert_eq!(negotiate(Some("application/json;q=0"), Encoding::Protobuf), Encoding::Protobuf);
+        assert_eq!(negotiate(Some("application/json;q=0.5, application/protobuf"), Encoding::Json), Encoding::Protobuf);
+        assert_eq!(negotiate(Some("application/json, application/protobuf"), Encoding::Protobuf), Encoding::Protobuf);
+    }
+}
+
+#[cfg(test)]
+mod compression_tests {
+    use super::*;
+
+    fn encoded_req(coding: &'static str, input: Vec<u8>) -> ServiceRequest<Vec<u8>> {
+        let mut req = ServiceRequest::new(input);
+        req.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(coding));
+        req
+    }
+
+    #[test]
+    fn unsupported_encoding() {
+        assert_eq!(encoded_req("identity", vec![1, 2]).decompress().unwrap().input, vec![1, 2]);
+        match encoded_req("br", vec![1, 2]).decompress().unwrap_err() {
+            ProstTwirpError::TwirpError(err) => {
+                assert_eq!(err.code, "malformed");
+                assert_eq!(err.msg, "Unsupported content encoding: br");
+            }
+            err => panic!("unexpected error: {:?}", err),
         }
     }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn gzip_round_trip() {
+        let req = ServiceRequest::new(b"hello hello hello".to_vec()).compress();
+        assert_eq!(req.headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(req.headers[hyper::header::ACCEPT_ENCODING], "gzip");
+        assert_ne!(req.input, b"hello hello hello".to_vec());
+
+        let req = req.decompress().unwrap();
+        assert!(!req.headers.contains_key(CONTENT_ENCODING));
+        assert_eq!(req.input, b"hello hello hello".to_vec());
+
+        assert!(encoded_req("gzip", vec![1, 2]).decompress().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn gzip_bombs() {
+        // A megabyte of zeros compresses to about a kilobyte
+        let bomb = || ServiceRequest::new(vec![0u8; 1 << 20]).compress();
+        assert!(bomb().input.len() < 4096);
+
+        let err = bomb().decompress_with_limit(Some(4096)).unwrap_err();
+        assert_eq!(err.to_string(), "twirp error: resource_exhausted: Request body too large");
+        assert_eq!(bomb().decompress_with_limit(Some(1 << 20)).unwrap().input.len(), 1 << 20);
+
+        let config = ServerConfig::new().with_max_body_size(4096);
+        let req = config.read_request(bomb().to_hyper_raw().unwrap()).wait().unwrap();
+        assert!(config.intercept_request(req).unwrap_err().is_twirp_code("resource_exhausted"));
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn accept_encoding_negotiation() {
+        let compressed = |accept: Option<&'static str>| {
+            let accept = accept.map(HeaderValue::from_static);
+            ServiceResponse::new(b"hello".to_vec()).compress_for(accept.as_ref()).headers.contains_key(CONTENT_ENCODING)
+        };
+        assert!(compressed(Some("gzip")));
+        assert!(compressed(Some("deflate, GZIP;q=0.5")));
+        assert!(compressed(Some("*")));
+        assert!(!compressed(Some("gzip;q=0")));
+        assert!(!compressed(Some("br")));
+        assert!(!compressed(None));
+    }
 }
 
 #[cfg(test)]
@@ -385,47 +1939,2031 @@ mod twirp_error_tests {
     }
 
     #[test]
-    fn deserialization() {
-        let err = TwirpError::from_json_bytes(StatusCode::INTERNAL_SERVER_ERROR, default_json().as_bytes());
+    fn canonical_constructors() {
+        let err = TwirpError::not_found("no such hat");
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+        assert_eq!(err.code, "not_found");
+        assert_eq!(err.msg, "no such hat");
+        assert_eq!(TwirpError::unavailable("down").status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn status_from_code() {
+        assert_eq!(TwirpError::new_code("permission_denied", "no").status, StatusCode::FORBIDDEN);
+        assert_eq!(TwirpError::code_to_status("made_up"), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn mismatched_status_is_corrected() {
+        let err = TwirpError::new(StatusCode::OK, "not_found", "no such hat");
+        assert_eq!(err.to_hyper_resp().unwrap().status(), StatusCode::NOT_FOUND);
+        let err = TwirpError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "bad_content_type", "nope");
+        assert_eq!(err.to_hyper_resp().unwrap().status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let err = TwirpError::new(StatusCode::METHOD_NOT_ALLOWED, "bad_route", "POST only");
+        assert_eq!(err.to_hyper_resp().unwrap().status(), StatusCode::METHOD_NOT_ALLOWED);
+        let err = TwirpError::new(StatusCode::BAD_REQUEST, "bad_route", "no such route");
+        assert_eq!(err.to_hyper_resp().unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(TwirpError::not_found("no such hat").to_string(), "not_found: no such hat");
+    }
+
+    #[test]
+    fn prost_twirp_error_display() {
+        let err = ProstTwirpError::from(TwirpError::not_found("no such hat"));
+        assert_eq!(err.to_string(), "twirp error: not_found: no such hat");
+        assert!(err.source().is_none());
+    }
+
+    #[test]
+    fn error_equality() {
+        let req = ServiceRequest::new(vec![0xff]);
+        let err = || req.to_proto::<Vec<u8>>().unwrap_err();
+        assert_eq!(err(), err());
+        assert_ne!(err(), req.body_err(TwirpError::malformed("bad").into()));
+        assert_eq!(ProstTwirpError::from(TwirpError::malformed("bad")), TwirpError::malformed("bad").into());
+        assert_ne!(ProstTwirpError::Timeout, ProstTwirpError::InvalidUri("x".into()));
+
+        assert!(req.body_err(TwirpError::not_found("hat").into()).is_twirp_code("not_found"));
+        assert!(!ProstTwirpError::from(TwirpError::not_found("hat")).is_twirp_code("bad_route"));
+        assert!(!ProstTwirpError::Timeout.is_twirp_code("deadline_exceeded"));
+    }
+
+    #[test]
+    fn after_body() {
+        let mut resp = ServiceResponse::new(b"<html>".to_vec());
+        let err = resp.to_proto::<Vec<u8>>().unwrap_err();
+        assert_eq!(err.after_body(), Some(&b"<html>"[..]));
+        assert_eq!(err.root_err().after_body(), None);
+
+        resp.output = Vec::new();
+        assert_eq!(resp.body_err(ProstTwirpError::Timeout).after_body(), Some(&[][..]));
+    }
+
+    #[test]
+    fn intermediary_errors() {
+        let mut resp = ServiceResponse::new(b"<html>Service Unavailable</html>".to_vec());
+        resp.status = StatusCode::SERVICE_UNAVAILABLE;
+        let err = resp.to_proto::<Vec<u8>>().unwrap_err();
+        assert_eq!(err.after_body(), Some(&b"<html>Service Unavailable</html>"[..]));
+
+        let err = err.twirp_error().unwrap();
+        assert_eq!((err.status, err.code.as_str()), (StatusCode::SERVICE_UNAVAILABLE, "unavailable"));
+        assert_eq!(err.msg, "Error from intermediary with HTTP status 503 Service Unavailable: <html>Service Unavailable</html>");
+        assert_eq!(err.meta_map().unwrap()["status_code"], "503");
+
+        let err = TwirpError::from_intermediary(StatusCode::FORBIDDEN, &[b'x'; 300]);
+        assert_eq!(err.code, "permission_denied");
+        assert!(err.msg.ends_with(&format!("{}...", "x".repeat(256))));
+        assert_eq!(TwirpError::from_intermediary(StatusCode::IM_A_TEAPOT, b"").code, "unknown");
+    }
+
+    #[test]
+    fn hyper_error_responses() {
+        let read = |resp: Response<Body>| ProstTwirpError::from_hyper_error_response::<()>(resp).wait().unwrap_err();
+
+        let err = read(TwirpError::not_found("No such hat").to_hyper_resp().unwrap());
+        assert_eq!((err.http_status(), err.twirp_code()), (Some(StatusCode::NOT_FOUND), Some("not_found")));
+        assert_eq!(err.twirp_error().unwrap().msg, "No such hat");
+
+        let resp = Response::builder().status(StatusCode::BAD_GATEWAY).body(Body::from("<html>Bad Gateway</html>")).unwrap();
+        let err = read(resp);
+        assert_eq!((err.twirp_code(), err.after_body()), (Some("unavailable"), Some(&b"<html>Bad Gateway</html>"[..])));
+
+        let err = read(Response::new(Body::from("ok")));
+        assert_eq!(err.twirp_code(), Some("internal"));
+        assert_eq!(err.twirp_error().unwrap().msg, "Expected an error response, got status 200 OK");
+    }
+
+    #[test]
+    fn classify() {
+        let wrapped = ServiceRequest::new(Vec::new()).body_err(TwirpError::not_found("hat").into());
+        assert_eq!(TwirpCode::classify(&wrapped), TwirpCode::NotFound);
+        assert_eq!(TwirpCode::classify(&TwirpError::new_code("made_up", "?").into()), TwirpCode::Unknown);
+        assert_eq!(TwirpCode::classify(&ProstTwirpError::Timeout), TwirpCode::DeadlineExceeded);
+        assert_eq!(TwirpCode::classify(&ProstTwirpError::InvalidUri("x".into())), TwirpCode::Internal);
+    }
+
+    #[test]
+    fn code_round_trip() {
+        for code in TwirpCode::ALL.iter() {
+            assert_eq!(code.as_str().parse::<TwirpCode>(), Ok(*code));
+            assert_eq!(TwirpError::new_code(code.as_str(), "?").status, code.default_status());
+        }
+        assert_eq!(TwirpError::new_code("made_up", "?").code_enum(), TwirpCode::Unknown);
+        assert_eq!(TwirpError::not_found("hat").code_enum().to_string(), "not_found");
+    }
+
+    #[test]
+    fn internal_from() {
+        #[derive(Debug)]
+        struct LoadError(std::io::Error);
+
+        impl fmt::Display for LoadError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("Couldn't load the hat catalog")
+            }
+        }
+
+        impl Error for LoadError {
+            fn source(&self) -> Option<&(dyn Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let load_error = || LoadError(std::io::Error::new(std::io::ErrorKind::NotFound, "/etc/hats.db not found"));
+        let err = TwirpError::internal_from(load_error());
+        assert_eq!((err.code.as_str(), err.msg.as_str()), ("internal", "Couldn't load the hat catalog"));
+        assert_eq!(err.meta, None);
+
+        let err = TwirpError::internal_from_with_sources(load_error());
+        assert_eq!(err.msg, "Couldn't load the hat catalog");
+        assert_eq!(err.meta_entry("causes"), Some("/etc/hats.db not found"));
+        assert_eq!(TwirpError::internal_from_with_sources(std::fmt::Error).meta, None);
+
+        let res: Result<(), _> = "x".parse::<i32>().map(|_| ()).map_err(TwirpError::internal_from);
+        assert_eq!(res.unwrap_err().msg, "invalid digit found in string");
+    }
+
+    #[test]
+    fn http_builder_errors() {
+        fn build() -> Result<Response<Body>, ProstTwirpError> {
+            Ok(Response::builder().header("bad header", "value").body(Body::empty())?)
+        }
+        let err = build().unwrap_err();
+        assert!(matches!(err, ProstTwirpError::HttpBuilderError(_)));
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let resp = ServiceResponse::from_hyper_raw(err.to_hyper_resp().unwrap()).wait().unwrap();
+        assert_eq!(resp.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(TwirpError::from_json_bytes(resp.status, &resp.output).unwrap().code, "internal");
+    }
+
+    #[test]
+    fn pretty_json() {
+        let err = default_error();
+        assert_eq!(String::from_utf8(err.to_json_bytes_pretty().unwrap()).unwrap(),
+            "{\n  \"code\": \"internal\",\n  \"msg\": \"Something went wrong\"\n}");
+        let tabs = serde_json::ser::PrettyFormatter::with_indent(b"\t");
+        assert_eq!(String::from_utf8(err.to_json_bytes_with(tabs).unwrap()).unwrap(),
+            "{\n\t\"code\": \"internal\",\n\t\"msg\": \"Something went wrong\"\n}");
+        assert_eq!(err.to_json_bytes_with(serde_json::ser::CompactFormatter).unwrap(), default_json().as_bytes());
+    }
+
+    #[test]
+    fn status_matches_code() {
+        let decode = |status, json: &str| TwirpError::from_json_bytes(status, json.as_bytes()).unwrap();
+        assert!(decode(StatusCode::INTERNAL_SERVER_ERROR, default_json()).status_matches_code());
+        assert!(!decode(StatusCode::BAD_REQUEST, default_json()).status_matches_code());
+        assert!(decode(StatusCode::IM_A_TEAPOT, r#"{"code":"teapot","msg":"Short and stout"}"#).status_matches_code());
+        assert!(decode(StatusCode::METHOD_NOT_ALLOWED, r#"{"code":"bad_route","msg":"GET"}"#).status_matches_code());
+    }
+
+    #[test]
+    fn status_code() {
+        let errors: Vec<ProstTwirpError> = vec![
+            Vec::<u8>::decode(&[0xff][..]).unwrap_err().into(),
+            serde_json::from_slice::<TwirpError>(b"{").unwrap_err().into(),
+            ProstTwirpError::Timeout,
+            TwirpError::not_found("No such hat").into(),
+            TwirpError::new(StatusCode::METHOD_NOT_ALLOWED, "bad_route", "GET").into(),
+            TwirpError::new(StatusCode::OK, "internal", "Wrong status").into(),
+            ServiceRequest::new(Vec::new()).body_err(TwirpError::unavailable("Try later").into()),
+            ProstTwirpError::InvalidUri("http://local host".to_string()),
+        ];
+        for err in errors {
+            let status = err.status_code();
+            assert_eq!(err.to_hyper_resp().unwrap().status(), status);
+        }
+    }
+
+    #[test]
+    fn code_and_status() {
+        let err = ServiceRequest::new(Vec::new()).body_err(TwirpError::permission_denied("no").into());
+        assert_eq!(err.twirp_code(), Some("permission_denied"));
+        assert_eq!(err.http_status(), Some(StatusCode::FORBIDDEN));
+        assert_eq!(ProstTwirpError::Timeout.twirp_code(), None);
+        assert_eq!(ProstTwirpError::Timeout.http_status(), None);
+    }
+
+    #[test]
+    fn error_conversions() {
+        fn decode_json(json: &[u8]) -> Result<TwirpError, ProstTwirpError> {
+            Ok(serde_json::from_slice(json)?)
+        }
+        fn decode_proto(proto: &[u8]) -> Result<Vec<u8>, ProstTwirpError> {
+            Ok(Vec::<u8>::decode(proto)?)
+        }
+        fn encode_proto(buf: &mut [u8]) -> Result<(), ProstTwirpError> {
+            Ok(vec![1u8; 10].encode(&mut std::io::Cursor::new(buf))?)
+        }
+        assert!(matches!(decode_json(b"{").unwrap_err(), ProstTwirpError::JsonDecodeError(_)));
+        assert!(matches!(decode_proto(&[0xff]).unwrap_err(), ProstTwirpError::ProstDecodeError(_)));
+        assert!(matches!(encode_proto(&mut [0u8; 2]).unwrap_err(), ProstTwirpError::ProstEncodeError(_)));
+    }
+
+    #[test]
+    fn deserialization() {
+        let err = TwirpError::from_json_bytes(StatusCode::INTERNAL_SERVER_ERROR, default_json().as_bytes());
         assert_eq!(err.unwrap(), default_error());
+
+        for json in &["{}", r#"{"msg":"No code"}"#, r#"{"code":"internal"}"#, r#"{"code":" ","msg":"Blank"}"#] {
+            assert!(TwirpError::from_json_bytes(StatusCode::INTERNAL_SERVER_ERROR, json.as_bytes()).is_err());
+        }
+
+        // Clients treat malformed error bodies like intermediary errors instead of empty codes
+        let mut resp = ServiceResponse::new("{}".as_bytes().to_vec());
+        resp.status = StatusCode::INTERNAL_SERVER_ERROR;
+        let err = resp.to_proto::<()>().unwrap_err().twirp_error().cloned().unwrap();
+        assert_eq!(err.code, "unknown");
+        assert_eq!(err.meta_entry("http_error_from_intermediary"), Some("true"));
+    }
+
+    #[test]
+    fn meta_round_trip() {
+        let meta = serde_json::json!({"retry_after": "5", "violations": [{"field": "inches", "reason": "negative"}]});
+        let err = TwirpError::new_meta(StatusCode::SERVICE_UNAVAILABLE, "unavailable", "Try later", Some(meta.clone()));
+        let resp = ServiceResponse::from_hyper_raw(err.to_hyper_resp().unwrap()).wait().unwrap();
+
+        let client_err = resp.to_proto::<Vec<u8>>().unwrap_err();
+        let client_err = client_err.twirp_error().unwrap();
+        assert_eq!(client_err, &err);
+        assert_eq!(client_err.meta, Some(meta));
+        assert_eq!(client_err.status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn field_violations() {
+        let err = TwirpError::invalid_argument_field("size", "must be positive")
+            .with_field_violation("color", "is required");
+        assert_eq!((err.code.as_str(), err.msg.as_str()), ("invalid_argument", "size must be positive"));
+        assert_eq!(err.argument(), Some("size"));
+
+        let decoded: TwirpError = serde_json::from_slice(&serde_json::to_vec(&err).unwrap()).unwrap();
+        let violations = decoded.field_violations();
+        assert_eq!(violations.len(), 2);
+        assert_eq!(violations["size"], "must be positive");
+        assert_eq!(violations["color"], "is required");
+        assert!(TwirpError::internal("oops").field_violations().is_empty());
+    }
+
+    #[test]
+    fn meta_entries() {
+        let err = TwirpError::unavailable("Try later").with_meta_entry("retry_after", "5").with_meta_entry("region", "eu");
+        let json = err.to_json_bytes().unwrap();
+        assert_eq!(String::from_utf8(json).unwrap(),
+            r#"{"code":"unavailable","msg":"Try later","meta":{"region":"eu","retry_after":"5"}}"#);
+
+        let meta = err.meta_map().unwrap();
+        assert_eq!(meta["retry_after"], "5");
+        assert_eq!(meta.len(), 2);
+
+        let nested = TwirpError::new_meta(StatusCode::BAD_REQUEST, "malformed", "bad", Some(serde_json::json!({"n": 1})));
+        assert_eq!(nested.meta_map(), None);
+        assert_eq!(nested.with_meta_entry("k", "v").meta.unwrap(), serde_json::json!({"n": 1, "k": "v"}));
+    }
+}
+
+#[cfg(test)]
+mod service_request_tests {
+    use super::*;
+    use prost_derive::Message;
+
+    #[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+    struct Size {
+        #[prost(int32, tag="1")]
+        #[serde(default)]
+        inches: i32,
+    }
+
+    #[test]
+    fn headers_survive_decoding() {
+        let mut req = ServiceRequest::new(Vec::new());
+        req.headers.insert("authorization", HeaderValue::from_static("Bearer s3cr3t"));
+        let req = req.to_proto::<Size>().unwrap();
+        assert_eq!(req.header("authorization"), Some("Bearer s3cr3t"));
+        assert_eq!(req.bearer_token(), Some("s3cr3t"));
+        assert_eq!(req.header("x-missing"), None);
+    }
+
+    #[test]
+    fn codecs() {
+        let registry = CodecRegistry::new().with_codec(JsonCodec);
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("Application/JSON; charset=utf-8"));
+        assert_eq!(registry.find(&headers).map(|codec| codec.media_type().to_string()), Some("application/json".to_string()));
+        headers.insert(CONTENT_TYPE, Encoding::Protobuf.content_type());
+        assert!(registry.find(&headers).is_none());
+
+        let req = ServiceRequest::new(Size { inches: 7 }).to_codec_raw(&JsonCodec).unwrap();
+        assert_eq!((req.header("content-type"), &req.input[..]), (Some("application/json"), &br#"{"inches":7}"#[..]));
+        assert_eq!(req.to_proto_codec::<Size>(&JsonCodec).unwrap().input, Size { inches: 7 });
+        assert_eq!(ServiceRequest::new(Vec::new()).to_proto_codec::<Size>(&JsonCodec).unwrap().input, Size { inches: 0 });
+        let err = ServiceRequest::new(b"{".to_vec()).to_proto_codec::<Size>(&JsonCodec).unwrap_err();
+        assert_eq!(err.twirp_code(), Some("malformed"));
+    }
+
+    #[test]
+    fn success_statuses() {
+        let resp = ServiceResponse::with_status(Size { inches: 7 }, StatusCode::CREATED).unwrap();
+        assert_eq!(resp.header("content-type"), Some("application/protobuf"));
+        assert_eq!(resp.to_proto_raw().unwrap().to_proto::<Size>().unwrap().status, StatusCode::CREATED);
+        assert_eq!(resp.to_hyper_proto().unwrap().status(), StatusCode::CREATED);
+        assert_eq!(resp.to_hyper_json().unwrap().status(), StatusCode::CREATED);
+        assert_eq!(resp.to_proto_raw().unwrap().into_hyper_streaming().unwrap().status(), StatusCode::CREATED);
+    }
+
+    #[test]
+    fn error_statuses_are_reserved() {
+        let err = ServiceResponse::with_status(Size { inches: 7 }, StatusCode::FOUND).unwrap_err();
+        assert_eq!(err.twirp_code(), Some("internal"));
+        assert_eq!(err.twirp_error().unwrap().msg, "ServiceResponse status 302 Found is not a success status");
+    }
+
+    #[test]
+    fn debug_redaction() {
+        let mut req = ServiceRequest::new(vec![7u8; 1000]);
+        req.headers.insert("authorization", HeaderValue::from_static("Bearer s3cr3t"));
+        req.headers.insert("cookie", HeaderValue::from_static("session=abc"));
+        req.headers.insert("x-api-key", HeaderValue::from_static("k3y"));
+        let debug = format!("{:?}", req);
+        assert!(!debug.contains("s3cr3t") && !debug.contains("session=abc"), "{}", debug);
+        assert!(debug.contains("\"authorization\": [redacted]") && debug.contains("k3y"), "{}", debug);
+        assert!(debug.contains("more chars)") && debug.len() < 1000, "{}", debug);
+
+        let mut resp = ServiceResponse::new(Size { inches: 7 });
+        resp.headers.insert("set-cookie", HeaderValue::from_static("session=abc"));
+        let debug = format!("{:?}", resp);
+        assert!(!debug.contains("session=abc") && debug.contains("inches: 7"), "{}", debug);
+
+        let redaction = RedactionConfig::new(&["authorization", "X-Api-Key"]);
+        let debug = format!("{:?}", req.debug_with(&redaction));
+        assert!(!debug.contains("s3cr3t") && !debug.contains("k3y") && debug.contains("session=abc"), "{}", debug);
+    }
+
+    #[test]
+    fn explicit_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer s3cr3t"));
+        let req = ServiceRequest::with_headers(Size { inches: 7 }, headers.clone());
+        assert_eq!(req.bearer_token(), Some("s3cr3t"));
+        assert_eq!(req.header("content-type"), Some("application/protobuf"));
+
+        headers.insert(CONTENT_TYPE, Encoding::Json.content_type());
+        let req = ServiceRequest::with_headers(Size { inches: 7 }, headers);
+        assert_eq!(req.header("content-type"), Some("application/json"));
+    }
+
+    #[test]
+    fn body_size_limit() {
+        let read = |limit| {
+            let chunks: Vec<Result<_, hyper::Error>> = vec![Ok(vec![0u8; 6]), Ok(vec![0u8; 6])];
+            let req = Request::new(Body::wrap_stream(futures::stream::iter_result(chunks)));
+            ServiceRequest::from_hyper_raw_with_limit(req, limit).wait()
+        };
+        assert_eq!(read(Some(12)).unwrap().input.len(), 12);
+
+        let err = read(Some(10)).unwrap_err();
+        assert_eq!(err.to_hyper_resp().unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let mut req = Request::new(Body::empty());
+        req.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from_static("1000"));
+        match ServiceRequest::from_hyper_raw_with_limit(req, Some(10)).wait().unwrap_err() {
+            ProstTwirpError::TwirpError(err) => assert_eq!(err.code, "resource_exhausted"),
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn body_presized_from_content_length() {
+        let read = |content_length| {
+            let chunks: Vec<Result<_, hyper::Error>> = vec![Ok(vec![0u8; 6]), Ok(vec![0u8; 6])];
+            let mut req = Request::new(Body::wrap_stream(futures::stream::iter_result(chunks)));
+            req.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from_static(content_length));
+            ServiceRequest::from_hyper_raw(req).wait().unwrap().input
+        };
+        assert_eq!(read("12").capacity(), 12);
+        // A wrong length only sizes the buffer, the whole body is still read
+        assert_eq!(read("4").len(), 12);
+        assert_eq!(read("99999999999").len(), 12);
+    }
+
+    #[test]
+    fn version_survives_hyper_conversion() {
+        let mut req = ServiceRequest::new(Size { inches: 7 });
+        req.version = Version::HTTP_2;
+        let hyper_req = req.to_hyper_proto().unwrap();
+        assert_eq!(hyper_req.version(), Version::HTTP_2);
+        assert_eq!(ServiceRequest::from_hyper_raw(hyper_req).wait().unwrap().version, Version::HTTP_2);
+
+        let mut resp = ServiceResponse::new(Size { inches: 7 });
+        resp.version = Version::HTTP_10;
+        let hyper_resp = resp.to_hyper_proto().unwrap();
+        assert_eq!(hyper_resp.version(), Version::HTTP_10);
+        assert_eq!(ServiceResponse::from_hyper_raw(hyper_resp).wait().unwrap().version, Version::HTTP_10);
+    }
+
+    #[test]
+    fn response_auto_decoding() {
+        let resp = |ct: &'static str, output: Vec<u8>| {
+            ServiceResponse::new(output).with_header(CONTENT_TYPE, HeaderValue::from_static(ct))
+        };
+        let proto = ServiceResponse::new(Size { inches: 7 }).to_proto_raw().unwrap().output;
+        assert_eq!(resp("application/protobuf", proto).to_proto_auto::<Size>().unwrap().output, Size { inches: 7 });
+        assert_eq!(resp("application/json; charset=utf-8", br#"{"inches":7}"#.to_vec()).to_proto_auto::<Size>().unwrap().output,
+            Size { inches: 7 });
+
+        let err = resp("text/html", b"<html>".to_vec()).to_proto_auto::<Size>().unwrap_err();
+        assert_eq!(err.twirp_code(), Some("bad_content_type"));
+        assert_eq!(err.twirp_error().unwrap().msg,
+            "Unsupported content type text/html, must be application/protobuf or application/json");
+
+        let mut failed = resp("application/json", br#"{"code":"not_found","msg":"no hat"}"#.to_vec());
+        failed.status = StatusCode::NOT_FOUND;
+        assert_eq!(failed.to_proto_auto::<Size>().unwrap_err().twirp_code(), Some("not_found"));
+    }
+
+    #[test]
+    fn handler_helpers() {
+        let resp = twirp_ok(Size { inches: 7 }).wait().unwrap();
+        assert_eq!(resp.output, Size { inches: 7 });
+        assert_eq!(resp.status, StatusCode::OK);
+        let err = twirp_err::<Size>(TwirpError::not_found("No such hat")).wait().unwrap_err();
+        assert!(err.is_twirp_code("not_found"));
+
+        assert_eq!(Size { inches: 7 }.into_res().wait().unwrap().output, Size { inches: 7 });
+        let resp = ServiceResponse::with_status(Size { inches: 7 }, StatusCode::ACCEPTED).unwrap();
+        assert_eq!(resp.into_res().wait().unwrap().status, StatusCode::ACCEPTED);
+    }
+
+    #[test]
+    fn rpc_method() {
+        let mut hyper_req = Request::new(Body::empty());
+        hyper_req.extensions_mut().insert(RpcMethod("MakeHat"));
+        let req = ServiceRequest::from_hyper_raw(hyper_req).wait().unwrap();
+        assert_eq!(req.rpc_method(), Some("MakeHat"));
+        assert_eq!(req.clone_with_input(()).rpc_method(), Some("MakeHat"));
+        assert_eq!(ServiceRequest::new(()).rpc_method(), None);
+    }
+
+    #[test]
+    fn query() {
+        let hyper_req = Request::post("/twirp/pkg.Svc/Method?trace=1").body(Body::empty()).unwrap();
+        let req = ServiceRequest::from_hyper_raw(hyper_req).wait().unwrap();
+        assert_eq!(req.uri.path(), "/twirp/pkg.Svc/Method");
+        assert_eq!(req.query(), Some("trace=1"));
+        assert_eq!(ServiceRequest::new(()).query(), None);
+    }
+
+    #[test]
+    fn empty_responses() {
+        let empty = ServiceResponse::new(Vec::new()).with_header("x-request-id", HeaderValue::from_static("abc"));
+        assert_eq!(empty.to_proto::<()>().unwrap().output, ());
+        let resp = empty.to_proto::<Size>().unwrap();
+        assert_eq!(resp.output, Size::default());
+        assert_eq!(resp.headers["x-request-id"], "abc");
+        assert_eq!(empty.to_proto_json::<Size>().unwrap().output, Size::default());
+
+        // Empty error bodies are still errors
+        let mut failed = empty.clone();
+        failed.status = StatusCode::SERVICE_UNAVAILABLE;
+        assert_eq!(failed.to_proto::<Size>().unwrap_err().twirp_code(), Some("unavailable"));
+    }
+
+    #[test]
+    fn clone_keeps_http_info() {
+        let mut req = ServiceRequest::new(Size { inches: 7 });
+        req.uri = "/twirp/pkg.Svc/Method".parse().unwrap();
+        req.version = Version::HTTP_2;
+        req.headers.insert("x-request-id", HeaderValue::from_static("abc"));
+        let copy = req.clone();
+        assert_eq!((copy.uri, copy.version, copy.input), (req.uri, req.version, req.input));
+        assert_eq!(copy.headers, req.headers);
+
+        let mut resp = ServiceResponse::new(Size { inches: 7 });
+        resp.status = StatusCode::ACCEPTED;
+        resp.headers.insert("x-request-id", HeaderValue::from_static("abc"));
+        let copy = resp.clone();
+        assert_eq!((copy.status, copy.version, copy.output), (resp.status, resp.version, resp.output));
+        assert_eq!(copy.headers, resp.headers);
+    }
+
+    #[test]
+    fn response_headers() {
+        let mut resp = ServiceResponse::new(());
+        resp.headers.insert("x-ratelimit-remaining", HeaderValue::from_static("42"));
+        resp.headers.append("set-cookie", HeaderValue::from_static("a=1"));
+        resp.headers.append("set-cookie", HeaderValue::from_bytes(b"b=\xff").unwrap());
+        resp.headers.append("set-cookie", HeaderValue::from_static("c=3"));
+        assert_eq!(resp.header("X-RateLimit-Remaining"), Some("42"));
+        assert_eq!(resp.header("x-missing"), None);
+        assert_eq!(resp.header_all("set-cookie"), vec!["a=1", "c=3"]);
+        assert!(resp.header_all("x-missing").is_empty());
+    }
+
+    #[test]
+    fn response_with_header() {
+        let resp = ServiceResponse::new(Size { inches: 7 })
+            .with_header("cache-control", HeaderValue::from_static("max-age=60"))
+            .with_header(hyper::header::CONTENT_LANGUAGE, HeaderValue::from_static("en"));
+        let hyper_resp = resp.to_hyper_proto().unwrap();
+        assert_eq!(hyper_resp.headers()["cache-control"], "max-age=60");
+        assert_eq!(hyper_resp.headers()["content-language"], "en");
+        assert_eq!(hyper_resp.headers()[CONTENT_TYPE], "application/protobuf");
+        assert_eq!(resp.to_json_raw().unwrap().header("cache-control"), Some("max-age=60"));
+    }
+
+    #[test]
+    fn json_decoding() {
+        let req = ServiceRequest::new(br#"{"inches":12}"#.to_vec());
+        assert_eq!(req.to_proto_json::<Size>().unwrap().input, Size { inches: 12 });
+    }
+
+    #[test]
+    fn json_empty_body() {
+        let req = ServiceRequest::new(Vec::new());
+        assert_eq!(req.to_proto_json::<Size>().unwrap().input, Size::default());
+    }
+
+    #[test]
+    fn json_malformed_body() {
+        let req = ServiceRequest::new(b"{inches".to_vec());
+        let resp = req.to_proto_json::<Size>().unwrap_err().to_hyper_resp().unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = resp.into_body().concat2().wait().unwrap();
+        let err = TwirpError::from_json_bytes(StatusCode::BAD_REQUEST, &body).unwrap();
+        assert_eq!((err.code.as_str(), err.msg.as_str()), ("malformed", "Invalid JSON body"));
+    }
+
+    #[test]
+    fn json_response() {
+        let resp = ServiceResponse::new(Size { inches: 12 }).to_hyper_json().unwrap();
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(resp.headers()[CONTENT_LENGTH], "13");
+    }
+}
+
+/// A hook invoked by server handlers around every RPC
+///
+/// Interceptors see the raw request before it is decoded, and the raw response (including error
+/// responses) before it is sent.
+pub trait Interceptor: Send + Sync {
+    /// Inspect a request before it is dispatched; returning an error rejects it
+    fn on_request(&self, _req: &ServiceRequest<Vec<u8>>) -> Result<(), TwirpError> {
+        Ok(())
+    }
+
+    /// Inspect a response before it is sent
+    fn on_response(&self, _resp: &ServiceResponse<Vec<u8>>) {}
+}
+
+/// The configuration of a generated server handler
+#[derive(Clone, Default)]
+pub struct ServerConfig {
+    /// The interceptors invoked around every RPC, in order
+    pub interceptors: Vec<Arc<dyn Interceptor>>,
+    /// The maximum size in bytes of a request body, if any
+    pub max_body_size: Option<usize>,
+    /// Whether to reject request bodies that don't match their `Content-Length`
+    pub check_content_length: bool,
+    /// Whether routes also match with a single trailing slash, e.g. `/twirp/pkg.Svc/Method/`
+    pub trailing_slash: bool,
+    /// The statuses error responses are sent with by code, overriding the canonical ones
+    pub status_overrides: HashMap<String, StatusCode>,
+    /// The size in bytes from which response bodies are streamed in chunks, if any
+    pub streaming_threshold: Option<usize>,
+    /// The codecs accepted in addition to protobuf and JSON, by servers generated with `generate_json`
+    pub codecs: CodecRegistry,
+    /// The longest a service method may take to respond, if limited
+    pub handler_timeout: Option<Duration>,
+    /// Whether error responses for undecodable request bodies include the decoder's message
+    pub decode_error_details: bool,
+}
+
+impl ServerConfig {
+    /// Create a configuration with no interceptors and no body size limit
+    pub fn new() -> ServerConfig {
+        ServerConfig::default()
+    }
+
+    /// Add an interceptor, invoked after all the previously added ones
+    pub fn with_interceptor<I: Interceptor + 'static>(mut self, interceptor: I) -> ServerConfig {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Reject request bodies larger than the given size in bytes with a `resource_exhausted` error
+    ///
+    /// The limit applies both to the body as sent and to the body once decompressed, so small
+    /// compressed bodies can't inflate past it.
+    pub fn with_max_body_size(self, max_body_size: usize) -> ServerConfig {
+        ServerConfig { max_body_size: Some(max_body_size), ..self }
+    }
+
+    /// Reject request bodies that don't match their `Content-Length` with a `malformed` error
+    ///
+    /// This catches truncated requests before decoding them. Requests without a `Content-Length`,
+    /// e.g. chunked ones, are not checked.
+    pub fn with_content_length_check(self, check_content_length: bool) -> ServerConfig {
+        ServerConfig { check_content_length, ..self }
+    }
+
+    /// Route paths with a single trailing slash like the same path without it
+    ///
+    /// This is off by default, as the spec only allows exact paths, but some proxies add the slash.
+    pub fn with_trailing_slash(self, trailing_slash: bool) -> ServerConfig {
+        ServerConfig { trailing_slash, ..self }
+    }
+
+    /// Send errors with the given codes with the given statuses, e.g. for gateways expecting them
+    ///
+    /// The statuses override those of canonical codes and of the errors themselves; custom codes
+    /// not in the table keep their own status, which `TwirpError::new_code` defaults to `500`.
+    pub fn with_status_overrides(mut self, status_overrides: HashMap<String, StatusCode>) -> ServerConfig {
+        self.status_overrides.extend(status_overrides);
+        self
+    }
+
+    /// Accept requests in the given codec's content type, and reply to them with it
+    ///
+    /// Only servers generated with `generate_json` use codecs, since they need serde derives.
+    pub fn with_codec<C: Codec + 'static>(mut self, codec: C) -> ServerConfig {
+        self.codecs = self.codecs.with_codec(codec);
+        self
+    }
+
+    /// Fail service methods that take longer than the given duration with a `deadline_exceeded` error
+    ///
+    /// The method's future is dropped once the timeout elapses, which cancels its work unless it
+    /// blocks the thread. The error has the canonical `408` status; map it to e.g. `504` with
+    /// `with_status_overrides` if needed.
+    pub fn with_handler_timeout(self, handler_timeout: Duration) -> ServerConfig {
+        ServerConfig { handler_timeout: Some(handler_timeout), ..self }
+    }
+
+    /// Include the decoder's message in error responses for undecodable request bodies
+    ///
+    /// This helps diagnose schema mismatches, e.g. `Invalid protobuf body: invalid wire type`, but
+    /// exposes details of the server's messages, so it is off by default. With the `tracing`
+    /// feature, the details are always logged as debug events.
+    pub fn with_decode_error_details(self, decode_error_details: bool) -> ServerConfig {
+        ServerConfig { decode_error_details, ..self }
+    }
+
+    /// Stream response bodies of at least the given size in bytes in chunks, without a `Content-Length`
+    ///
+    /// This keeps a single copy of large bodies in memory while they are sent.
+    pub fn with_streaming_responses(self, streaming_threshold: usize) -> ServerConfig {
+        ServerConfig { streaming_threshold: Some(streaming_threshold), ..self }
+    }
+
+    /// The status the given error is sent with, taking the status overrides into account
+    pub fn error_status(&self, err: &TwirpError) -> StatusCode {
+        self.status_overrides.get(&err.code).cloned().unwrap_or_else(|| err.wire_status())
+    }
+
+    /// The path a request is routed by, without a trailing slash if they are allowed
+    pub fn route_path<'a>(&self, path: &'a str) -> &'a str {
+        match path.strip_suffix('/') {
+            Some(path) if self.trailing_slash => path,
+            _ => path,
+        }
+    }
+
+    /// Read the body of the given request, enforcing the maximum body size and `Content-Length` check
+    pub fn read_request(&self, req: Request<Body>) -> FutReq<Vec<u8>> {
+        let read = Box::new(ServiceRequest::from_hyper_raw_with_limit(req, self.max_body_size).map(|req| {
+            count_body_bytes("twirp_server_request_bytes", req.uri.path(), req.input.len());
+            req
+        }));
+        if !self.check_content_length {
+            return read;
+        }
+        Box::new(read.and_then(|req| req.check_content_length().map(|_| req)))
+    }
+
+    /// Decompress the request and run the request interceptors, stopping at the first one that rejects it
+    pub fn intercept_request(&self, req: ServiceRequest<Vec<u8>>) -> Result<ServiceRequest<Vec<u8>>, ProstTwirpError> {
+        let req = req.decompress_with_limit(self.max_body_size)?;
+        for interceptor in &self.interceptors {
+            interceptor.on_request(&req)?;
+        }
+        Ok(req)
+    }
+
+    /// Limit the given service method response to the handler timeout, if any
+    pub fn apply_handler_timeout(&self, resp: PTRes<Vec<u8>>) -> PTRes<Vec<u8>> {
+        match self.handler_timeout {
+            Some(timeout) => Box::new(Timeout::new(resp, timeout).map_err(move |err| {
+                if err.is_elapsed() {
+                    TwirpError::deadline_exceeded(&format!("Handler did not respond within {:?}", timeout)).into()
+                } else {
+                    err.into_inner().unwrap_or_else(|| TwirpError::internal("Handler timer failed").into())
+                }
+            })),
+            None => resp,
+        }
+    }
+
+    /// Run the response interceptors and turn the response into a hyper response
+    ///
+    /// The response is gzipped if the request's `Accept-Encoding` allows it, and streamed if it is
+    /// large enough.
+    pub fn intercept_response(&self, accept_encoding: Option<&HeaderValue>, resp: ServiceResponse<Vec<u8>>)
+            -> Result<Response<Body>, ProstTwirpError> {
+        for interceptor in &self.interceptors {
+            interceptor.on_response(&resp);
+        }
+        let resp = resp.compress_for(accept_encoding);
+        match self.streaming_threshold {
+            Some(threshold) if resp.output.len() >= threshold => resp.into_hyper_streaming(),
+            _ => resp.to_hyper_raw(),
+        }
+    }
+
+    /// Run the response interceptors on the response for the given error and turn it into a hyper response
+    pub fn intercept_error(&self, err: ProstTwirpError) -> Result<Response<Body>, hyper::Error> {
+        #[cfg(feature = "tracing")]
+        {
+            if err.is_decode_error() {
+                tracing::debug!(error = %err, "undecodable request body");
+            }
+        }
+        let err = err.into_twirp_error_with(self.decode_error_details)?;
+        let mut resp = err.to_resp_raw();
+        resp.status = self.error_status(&err);
+        for interceptor in &self.interceptors {
+            interceptor.on_response(&resp);
+        }
+        Ok(error_resp(resp.to_hyper_raw()))
+    }
+}
+
+/// Count the given number of body bytes, as sent, in the named `metrics` counter, labelled by path
+///
+/// The counters are `twirp_server_request_bytes`, `twirp_server_response_bytes`,
+/// `twirp_client_request_bytes` and `twirp_client_response_bytes`. Without the `metrics` feature,
+/// this does nothing.
+fn count_body_bytes(name: &'static str, path: &str, len: usize) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!(name, "path" => path.to_string()).increment(len as u64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = (name, path, len);
+}
+
+/// Run a generated server handler for the given request within a `twirp.server` tracing span
+///
+/// Span names must be static, so the full Twirp method path is in the span's `path` field, the
+/// `RpcMethod` of the request in its `rpc_method` field, and the response status or error is
+/// recorded once the handler completes. Without the `tracing` feature, this just runs the handler.
+///
+/// With the `metrics` feature, the sizes of responses with a `Content-Length` are counted too.
+pub fn instrument_server<F>(req: Request<Body>, handler: F) -> FutHyperResp
+        where F: FnOnce(Request<Body>) -> FutHyperResp {
+    #[cfg(feature = "metrics")]
+    let handler = {
+        let path = req.uri().path().to_string();
+        move |req| -> FutHyperResp {
+            Box::new(handler(req).map(move |resp| {
+                let len = resp.headers().get(CONTENT_LENGTH)
+                    .and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok());
+                if let Some(len) = len {
+                    count_body_bytes("twirp_server_response_bytes", &path, len);
+                }
+                resp
+            }))
+        }
+    };
+    #[cfg(feature = "tracing")]
+    {
+        use tracing_futures::Instrument;
+
+        let span = tracing::info_span!("twirp.server", path = req.uri().path(), method = %req.method(),
+            rpc_method = req.extensions().get::<RpcMethod>().map(|method| method.0),
+            status = tracing::field::Empty, error = tracing::field::Empty);
+        let record = span.clone();
+        let resp = span.in_scope(|| handler(req)).then(move |res| {
+            match res {
+                Ok(ref resp) => { record.record("status", resp.status().as_u16()); }
+                Err(ref err) => { record.record("error", tracing::field::display(err)); }
+            }
+            res
+        });
+        Box::new(resp.instrument(span))
+    }
+    #[cfg(not(feature = "tracing"))]
+    handler(req)
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod metrics_tests {
+    use super::*;
+    use metrics::{Counter, CounterFn, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+    use std::sync::Mutex;
+
+    /// A recorder of counter totals, by name and path
+    #[derive(Default)]
+    struct Totals(Arc<Mutex<HashMap<(String, String), u64>>>);
+
+    struct Total(Arc<Mutex<HashMap<(String, String), u64>>>, (String, String));
+
+    impl CounterFn for Total {
+        fn increment(&self, value: u64) {
+            *self.0.lock().unwrap().entry(self.1.clone()).or_insert(0) += value;
+        }
+
+        fn absolute(&self, _value: u64) {}
+    }
+
+    impl Recorder for Totals {
+        fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+        fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+        fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+            let path = key.labels().find(|label| label.key() == "path").map_or("", |label| label.value());
+            Counter::from_arc(Arc::new(Total(self.0.clone(), (key.name().to_string(), path.to_string()))))
+        }
+
+        fn register_gauge(&self, _key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, _key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    impl Totals {
+        fn get(&self, name: &str, path: &str) -> Option<u64> {
+            self.0.lock().unwrap().get(&(name.to_string(), path.to_string())).cloned()
+        }
+    }
+
+    #[test]
+    fn body_bytes() {
+        let totals = Totals::default();
+        let path = "/twirp/pkg.Svc/Method";
+
+        metrics::with_local_recorder(&totals, || {
+            for body in &["hello", "hi"] {
+                let req = Request::post(path).body(Body::from(*body)).unwrap();
+                let resp = instrument_server(req, |req| {
+                    Box::new(ServerConfig::new().read_request(req).then(|req| {
+                        Ok(ServiceResponse::new(req.unwrap().input.repeat(2)).to_hyper_raw().unwrap())
+                    }))
+                });
+                resp.wait().unwrap();
+            }
+
+            let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+                .serve(|| hyper::service::service_fn_ok(|_| ServiceResponse::new(vec![1u8; 3]).to_hyper_raw().unwrap()));
+            let client = HyperClient::new(Client::new(), &format!("http://{}", server.local_addr()));
+            let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+            runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+            runtime.block_on(client.go_raw(path, ServiceRequest::new(vec![1u8; 10]))).unwrap();
+        });
+
+        assert_eq!(totals.get("twirp_server_request_bytes", path), Some(7));
+        assert_eq!(totals.get("twirp_server_response_bytes", path), Some(14));
+        assert_eq!(totals.get("twirp_client_request_bytes", path), Some(10));
+        assert_eq!(totals.get("twirp_client_response_bytes", path), Some(3));
+    }
+}
+
+#[cfg(test)]
+mod server_config_tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct RequireAuth;
+
+    impl Interceptor for RequireAuth {
+        fn on_request(&self, req: &ServiceRequest<Vec<u8>>) -> Result<(), TwirpError> {
+            match req.headers.get("authorization") {
+                Some(_) => Ok(()),
+                None => Err(TwirpError::unauthenticated("Missing credentials")),
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordStatus(Mutex<Vec<StatusCode>>);
+
+    impl Interceptor for Arc<RecordStatus> {
+        fn on_response(&self, resp: &ServiceResponse<Vec<u8>>) {
+            self.0.lock().unwrap().push(resp.status);
+        }
+    }
+
+    #[test]
+    fn interceptors() {
+        let statuses = Arc::new(RecordStatus::default());
+        let config = ServerConfig::new().with_interceptor(RequireAuth).with_interceptor(statuses.clone());
+
+        let mut req = ServiceRequest::new(Vec::new());
+        let err = config.intercept_request(req.clone_with_input(Vec::new())).unwrap_err();
+        assert_eq!(config.intercept_error(err).unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        req.headers.insert("authorization", HeaderValue::from_static("Bearer token"));
+        config.intercept_request(req).unwrap();
+        assert_eq!(config.intercept_response(None, ServiceResponse::new(Vec::new())).unwrap().status(), StatusCode::OK);
+
+        assert_eq!(*statuses.0.lock().unwrap(), vec![StatusCode::UNAUTHORIZED, StatusCode::OK]);
+    }
+
+    #[test]
+    fn content_length_check() {
+        let read = |config: &ServerConfig, content_length: &'static str, body: &'static str| {
+            let mut req = Request::new(Body::from(body));
+            req.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from_static(content_length));
+            config.read_request(req).wait().map(|req| req.input).map_err(|err| err.to_string())
+        };
+        let config = ServerConfig::new().with_content_length_check(true);
+        assert_eq!(read(&config, "5", "hello"), Ok(b"hello".to_vec()));
+        assert_eq!(read(&config, "10", "hello"),
+            Err("twirp error: malformed: Request body has 5 bytes but its Content-Length is 10".to_string()));
+        assert_eq!(read(&config, "five", "hello"), Err("twirp error: malformed: Invalid Content-Length".to_string()));
+        assert_eq!(read(&ServerConfig::new(), "10", "hello"), Ok(b"hello".to_vec()));
+
+        let chunked = ServiceRequest::new(b"hello".to_vec());
+        assert!(chunked.check_content_length().is_ok());
+    }
+
+    #[test]
+    fn trailing_slash() {
+        let path = "/twirp/pkg.Svc/Method/";
+        assert_eq!(ServerConfig::new().route_path(path), path);
+        let config = ServerConfig::new().with_trailing_slash(true);
+        assert_eq!(config.route_path(path), "/twirp/pkg.Svc/Method");
+        assert_eq!(config.route_path("/twirp/pkg.Svc/Method//"), "/twirp/pkg.Svc/Method/");
+    }
+
+    #[test]
+    fn streaming_responses() {
+        let output = vec![7u8; STREAMING_CHUNK_SIZE * 2 + 1];
+        let config = ServerConfig::new().with_streaming_responses(1024);
+
+        let resp = config.intercept_response(None, ServiceResponse::new(output.clone())).unwrap();
+        assert!(!resp.headers().contains_key(CONTENT_LENGTH));
+        let chunks: Vec<_> = resp.into_body().collect().wait().unwrap();
+        assert_eq!(chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![STREAMING_CHUNK_SIZE, STREAMING_CHUNK_SIZE, 1]);
+        assert!(chunks.iter().all(|chunk| chunk.iter().all(|&b| b == 7)));
+
+        let resp = config.intercept_response(None, ServiceResponse::new(vec![7u8; 10])).unwrap();
+        assert_eq!(resp.headers()[CONTENT_LENGTH], "10");
+
+        // Typed responses decode back from the chunks like any other
+        let resp = ServiceResponse::new(output.clone()).to_hyper_proto_streaming().unwrap();
+        let resp = ServiceResponse::<Vec<u8>>::from_hyper_proto(resp).wait().unwrap();
+        assert_eq!(resp.output, output);
+    }
+
+    #[test]
+    fn status_overrides() {
+        let mut overrides = HashMap::new();
+        overrides.insert("out_of_stock".to_string(), StatusCode::CONFLICT);
+        overrides.insert("unavailable".to_string(), StatusCode::BAD_GATEWAY);
+        let config = ServerConfig::new().with_status_overrides(overrides);
+
+        let status = |err: TwirpError| config.intercept_error(err.into()).unwrap().status();
+        assert_eq!(status(TwirpError::new_code("out_of_stock", "No hats left")), StatusCode::CONFLICT);
+        assert_eq!(status(TwirpError::unavailable("Try later")), StatusCode::BAD_GATEWAY);
+        assert_eq!(status(TwirpError::not_found("No such hat")), StatusCode::NOT_FOUND);
+        assert_eq!(status(TwirpError::new_code("sold_out", "No hats left")), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let resp = ProstTwirpError::from(TwirpError::unavailable("Try later")).to_hyper_resp_with_config(&config);
+        assert_eq!(resp.unwrap().status(), StatusCode::BAD_GATEWAY);
+        let resp = ProstTwirpError::from(TwirpError::unavailable("Try later")).to_hyper_resp();
+        assert_eq!(resp.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn decode_error_details() {
+        let decode_err = || ServiceRequest::new(vec![0xff]).to_proto::<String>().unwrap_err();
+        let msg = |config: &ServerConfig, err: ProstTwirpError| {
+            let resp = config.intercept_error(err).unwrap();
+            let body = resp.into_body().concat2().wait().unwrap();
+            TwirpError::from_json_bytes(StatusCode::BAD_REQUEST, &body).unwrap().msg
+        };
+
+        assert_eq!(msg(&ServerConfig::new(), decode_err()), "Invalid protobuf body");
+        let config = ServerConfig::new().with_decode_error_details(true);
+        let detailed = msg(&config, decode_err());
+        assert!(detailed.starts_with("Invalid protobuf body: ") && detailed.len() > 23, "{}", detailed);
+        let json_err = ServiceRequest::new(b"{".to_vec()).to_proto_json::<String>().unwrap_err();
+        assert!(msg(&config, json_err).starts_with("Invalid JSON body: "));
+
+        // Other errors are unaffected
+        assert_eq!(msg(&config, TwirpError::invalid_argument("Bad size").into()), "Bad size");
+        let resp = decode_err().to_hyper_resp_with_config(&config).unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn handler_timeout() {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let config = ServerConfig::new().with_handler_timeout(Duration::from_millis(20));
+        let err = runtime.block_on(config.apply_handler_timeout(Box::new(future::empty()))).unwrap_err();
+        assert_eq!(err.twirp_code(), Some("deadline_exceeded"));
+        assert_eq!(config.intercept_error(err).unwrap().status(), StatusCode::REQUEST_TIMEOUT);
+
+        let resp = runtime.block_on(config.apply_handler_timeout(twirp_ok(vec![1, 2]))).unwrap();
+        assert_eq!(resp.output, vec![1, 2]);
+        let err = runtime.block_on(config.apply_handler_timeout(twirp_err(TwirpError::not_found("No such hat")))).unwrap_err();
+        assert_eq!(err.twirp_code(), Some("not_found"));
+
+        // Without a timeout, responses are passed through as they are
+        let resp = ServerConfig::new().apply_handler_timeout(twirp_ok(vec![3])).wait().unwrap();
+        assert_eq!(resp.output, vec![3]);
+    }
+
+    #[test]
+    fn instrumented_handler() {
+        let resp = instrument_server(Request::new(Body::empty()), |req| {
+            assert_eq!(req.method(), Method::GET);
+            Box::new(future::ok(Response::new(Body::empty())))
+        });
+        assert_eq!(resp.wait().unwrap().status(), StatusCode::OK);
+    }
+}
+
+/// A router that dispatches requests across several generated services
+///
+/// Every route is given the request in order, e.g. `|req| Haberdasher::try_server_handler(svc.clone(), req)`,
+/// until one of them handles it. Requests that no route handles go to the fallback, or get a
+/// `bad_route` error without one.
+#[derive(Default)]
+pub struct TwirpRouter {
+    routes: Vec<Box<dyn Fn(Request<Body>) -> RouteResult + Send + Sync>>,
+    fallback: Option<Box<dyn Fn(Request<Body>) -> FutHyperResp + Send + Sync>>,
+}
+
+impl TwirpRouter {
+    /// Create a router with no routes
+    pub fn new() -> TwirpRouter {
+        TwirpRouter { routes: Vec::new(), fallback: None }
+    }
+
+    /// Handle requests no route handles with the given function, e.g. the rest of a hyper app
+    pub fn fallback<F>(self, fallback: F) -> TwirpRouter
+            where F: Fn(Request<Body>) -> FutHyperResp + Send + Sync + 'static {
+        TwirpRouter { fallback: Some(Box::new(fallback)), ..self }
+    }
+
+    /// Add a route, tried after all the previously added ones
+    pub fn route<F>(mut self, route: F) -> TwirpRouter
+            where F: Fn(Request<Body>) -> RouteResult + Send + Sync + 'static {
+        self.routes.push(Box::new(route));
+        self
+    }
+
+    /// Dispatch the request to the first route that handles it
+    pub fn handle(&self, req: Request<Body>) -> FutHyperResp {
+        let mut req = req;
+        for route in &self.routes {
+            match route(req) {
+                Ok(resp) => return resp,
+                Err(unhandled) => req = unhandled,
+            }
+        }
+        if let Some(ref fallback) = self.fallback {
+            return fallback(req);
+        }
+        Box::new(future::result(ProstTwirpError::from(TwirpError::bad_route("RPC Path not found")).to_hyper_resp()))
+    }
+}
+
+/// Answer `GET /healthz` with a `200 OK` and a small JSON body, or give the request back otherwise
+///
+/// This is a route for a `TwirpRouter`, e.g. `TwirpRouter::new().route(health_check)`, or can be
+/// tried before a generated handler, e.g. `health_check(req).unwrap_or_else(|req| Haberdasher::server_handler(svc, req))`.
+pub fn health_check(req: Request<Body>) -> RouteResult {
+    if req.uri().path() != "/healthz" || (req.method() != Method::GET && req.method() != Method::HEAD) {
+        return Err(req);
+    }
+    let body: &'static [u8] = br#"{"status":"ok"}"#;
+    let mut resp = Response::new(if req.method() == Method::HEAD { Body::empty() } else { Body::from(body) });
+    resp.headers_mut().insert(CONTENT_TYPE, Encoding::Json.content_type());
+    resp.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(body.len() as u64));
+    Ok(Box::new(future::ok(resp)))
+}
+
+/// A hyper service factory calling the same handler for every connection
+///
+/// It can be given to `hyper::Server::serve` directly, e.g. with a generated `server_handler`
+/// or a `TwirpRouter`: `TwirpServer::new(move |req| router.handle(req))`. Generated services
+/// also have a `new_server` shortcut for this.
+pub struct TwirpServer<F> {
+    handler: Arc<F>,
+}
+
+impl<F> TwirpServer<F> where F: Fn(Request<Body>) -> FutHyperResp + Send + Sync + 'static {
+    /// Serve every request with the given handler
+    pub fn new(handler: F) -> TwirpServer<F> {
+        TwirpServer { handler: Arc::new(handler) }
+    }
+}
+
+impl<F> Clone for TwirpServer<F> {
+    fn clone(&self) -> TwirpServer<F> {
+        TwirpServer { handler: self.handler.clone() }
+    }
+}
+
+impl<F> hyper::service::NewService for TwirpServer<F> where F: Fn(Request<Body>) -> FutHyperResp + Send + Sync + 'static {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Service = TwirpServer<F>;
+    type Future = future::FutureResult<TwirpServer<F>, hyper::Error>;
+    type InitError = hyper::Error;
+
+    fn new_service(&self) -> Self::Future {
+        future::ok(self.clone())
+    }
+}
+
+impl<F> hyper::service::Service for TwirpServer<F> where F: Fn(Request<Body>) -> FutHyperResp + Send + Sync + 'static {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = FutHyperResp;
+
+    fn call(&mut self, req: Request<Body>) -> FutHyperResp {
+        (self.handler)(req)
+    }
+}
+
+#[cfg(test)]
+mod twirp_router_tests {
+    use super::*;
+
+    fn route(path: &'static str) -> impl Fn(Request<Body>) -> RouteResult + Send + Sync {
+        move |req| if req.uri().path() == path {
+            Ok(Box::new(future::ok(Response::new(Body::from(path)))))
+        } else {
+            Err(req)
+        }
+    }
+
+    fn status(router: &TwirpRouter, path: &str) -> StatusCode {
+        let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+        router.handle(req).wait().unwrap().status()
+    }
+
+    #[test]
+    fn dispatch() {
+        let router = TwirpRouter::new().route(route("/twirp/a.A/Method")).route(route("/twirp/b.B/Method"));
+        assert_eq!(status(&router, "/twirp/a.A/Method"), StatusCode::OK);
+        assert_eq!(status(&router, "/twirp/b.B/Method"), StatusCode::OK);
+        assert_eq!(status(&router, "/twirp/c.C/Method"), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn server_connections_share_handler() {
+        use hyper::service::{NewService, Service};
+        let router = Arc::new(TwirpRouter::new().route(route("/twirp/a.A/Method")));
+        let server = TwirpServer::new(move |req| router.handle(req));
+        for _ in 0..2 {
+            let mut connection = server.new_service().wait().unwrap();
+            let resp = connection.call(Request::post("/twirp/a.A/Method").body(Body::empty()).unwrap());
+            assert_eq!(resp.wait().unwrap().status(), StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn fallback() {
+        let router = TwirpRouter::new().route(route("/twirp/a.A/Method")).fallback(|req| {
+            let mut resp = Response::new(Body::from(req.uri().path().to_string()));
+            *resp.status_mut() = StatusCode::IM_A_TEAPOT;
+            Box::new(future::ok(resp))
+        });
+        assert_eq!(status(&router, "/twirp/a.A/Method"), StatusCode::OK);
+        assert_eq!(status(&router, "/static/hat.png"), StatusCode::IM_A_TEAPOT);
+    }
+
+    #[test]
+    fn health_check_route() {
+        let router = TwirpRouter::new().route(health_check);
+        let resp = router.handle(Request::get("/healthz").body(Body::empty()).unwrap()).wait().unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(&resp.into_body().concat2().wait().unwrap()[..], br#"{"status":"ok"}"#);
+
+        assert_eq!(status(&router, "/healthz/more"), StatusCode::NOT_FOUND);
+        assert!(health_check(Request::post("/healthz").body(Body::empty()).unwrap()).is_err());
+    }
+}
+
+/// When and how often a client retries a failed request
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one
+    pub max_attempts: u32,
+    /// The delay before the first retry
+    pub base_delay: Duration,
+    /// The factor the delay is multiplied by after every retry
+    pub backoff: u32,
+    /// The Twirp error codes that are retried
+    ///
+    /// Client timeouts count as `deadline_exceeded`.
+    pub retryable_codes: Vec<String>,
+    /// Whether failures to connect to the server are retried
+    pub retry_connect_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    /// Three attempts with a 100ms exponential backoff, retrying only connection errors,
+    /// `unavailable` and `deadline_exceeded`
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            backoff: 2,
+            retryable_codes: vec!["unavailable".to_string(), "deadline_exceeded".to_string()],
+            retry_connect_errors: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether the given error should be retried under this policy
+    pub fn is_retryable(&self, err: &ProstTwirpError) -> bool {
+        match err {
+            ProstTwirpError::AfterBodyError { err, .. } => self.is_retryable(err),
+            ProstTwirpError::TwirpError(err) => self.retryable_codes.contains(&err.code),
+            ProstTwirpError::Timeout => self.retryable_codes.iter().any(|code| code == "deadline_exceeded"),
+            ProstTwirpError::HyperError(err) => self.retry_connect_errors && err.is_connect(),
+            _ => false,
+        }
+    }
+
+    /// The delay before the given retry, starting at 1
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.base_delay * self.backoff.saturating_pow(retry.saturating_sub(1))
     }
 }
 
 /// A wrapper for a hyper client
+///
+/// The client is generic over its connector, so e.g. a TLS connector can be used for HTTPS.
 #[derive(Debug)]
-pub struct HyperClient {
+pub struct HyperClient<C = HttpConnector> {
     /// The hyper client
-    pub client: Client<HttpConnector, Body>,
-    /// The root URL without any path attached
+    pub client: Client<C, Body>,
+    /// The root URL requests are sent under, e.g. `https://host` or `https://host/api`; see `url`
     pub root_url: String,
+    /// The maximum duration of every request, if any
+    pub timeout: Option<Duration>,
+    /// Headers added to every request, unless the request already sets them
+    pub default_headers: HeaderMap<HeaderValue>,
+    /// How failed requests are retried, if at all
+    pub retry_policy: Option<RetryPolicy>,
+    /// Whether request bodies are gzipped and gzipped responses are accepted
+    #[cfg(feature = "compression")]
+    pub gzip: bool,
+    /// Whether the underlying client only speaks HTTP/2, so requests are sent as HTTP/2
+    pub http2_only: bool,
+    /// Whether the timeout of every request is sent in `TIMEOUT_HEADER`, true by default
+    pub deadline_header: bool,
+    /// A hook run on every request right before it is sent, once all of its headers are set
+    pub request_hook: Option<RequestHook>,
+    /// The maximum size in bytes of a response body, if any
+    pub max_response_size: Option<usize>,
+    /// The `User-Agent` of every request that doesn't set one, `DEFAULT_USER_AGENT` by default
+    pub user_agent: Option<HeaderValue>,
+}
+
+/// The `User-Agent` clients send by default, e.g. `twirp-rs/0.1.0`
+pub const DEFAULT_USER_AGENT: &str = concat!("twirp-rs/", env!("CARGO_PKG_VERSION"));
+
+/// A hook run on every outgoing client request, e.g. to sign it
+#[derive(Clone)]
+pub struct RequestHook(Arc<RequestHookFn>);
+
+type RequestHookFn = dyn Fn(&mut Request<Body>) + Send + Sync;
+
+impl RequestHook {
+    /// Wrap the given function
+    pub fn new<F: Fn(&mut Request<Body>) + Send + Sync + 'static>(hook: F) -> RequestHook {
+        RequestHook(Arc::new(hook))
+    }
+
+    /// Run the hook on the given request
+    pub fn run(&self, req: &mut Request<Body>) {
+        (self.0)(req)
+    }
+}
+
+impl fmt::Debug for RequestHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("RequestHook")
+    }
+}
+
+/// A builder for a `HyperClient` and its underlying hyper client
+#[derive(Debug)]
+pub struct HyperClientBuilder<C = HttpConnector> {
+    connector: C,
+    root_url: String,
+    timeout: Option<Duration>,
+    retry_policy: Option<RetryPolicy>,
+    default_headers: HeaderMap<HeaderValue>,
+    #[cfg(feature = "compression")]
+    gzip: bool,
+    http2_only: bool,
+    deadline_header: bool,
+    request_hook: Option<RequestHook>,
+    max_response_size: Option<usize>,
+    user_agent: Option<HeaderValue>,
+    pool_idle_timeout: Option<Duration>,
+    pool_max_idle_per_host: usize,
+}
+
+impl HyperClientBuilder<HttpConnector> {
+    /// Create a builder for a plain HTTP client
+    pub fn new() -> HyperClientBuilder<HttpConnector> {
+        HyperClientBuilder {
+            connector: HttpConnector::new(4),
+            root_url: String::new(),
+            timeout: None,
+            retry_policy: None,
+            default_headers: HeaderMap::new(),
+            #[cfg(feature = "compression")]
+            gzip: false,
+            http2_only: false,
+            deadline_header: true,
+            request_hook: None,
+            max_response_size: None,
+            user_agent: Some(HeaderValue::from_static(DEFAULT_USER_AGENT)),
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            pool_max_idle_per_host: usize::MAX,
+        }
+    }
+}
+
+impl Default for HyperClientBuilder<HttpConnector> {
+    fn default() -> HyperClientBuilder<HttpConnector> { HyperClientBuilder::new() }
+}
+
+impl<C> HyperClientBuilder<C> {
+    /// Set the root URL requests are sent under, e.g. `https://host` or `https://host/api`
+    ///
+    /// See `HyperClient::url` for how it is joined with request paths.
+    pub fn root_url(self, root_url: &str) -> HyperClientBuilder<C> {
+        HyperClientBuilder { root_url: root_url.to_string(), ..self }
+    }
+
+    /// Set the maximum duration of every request
+    pub fn timeout(self, timeout: Duration) -> HyperClientBuilder<C> {
+        HyperClientBuilder { timeout: Some(timeout), ..self }
+    }
+
+    /// Set how failed requests are retried
+    pub fn retry_policy(self, retry_policy: RetryPolicy) -> HyperClientBuilder<C> {
+        HyperClientBuilder { retry_policy: Some(retry_policy), ..self }
+    }
+
+    /// Set the headers added to every request
+    pub fn default_headers(self, default_headers: HeaderMap<HeaderValue>) -> HyperClientBuilder<C> {
+        HyperClientBuilder { default_headers, ..self }
+    }
+
+    /// Gzip every request body and accept gzipped responses
+    #[cfg(feature = "compression")]
+    pub fn gzip(self, gzip: bool) -> HyperClientBuilder<C> {
+        HyperClientBuilder { gzip, ..self }
+    }
+
+    /// Only speak HTTP/2 to the server, with prior knowledge for plain HTTP (h2c)
+    ///
+    /// Over TLS, the connector must negotiate `h2` through ALPN.
+    pub fn http2_only(self, http2_only: bool) -> HyperClientBuilder<C> {
+        HyperClientBuilder { http2_only, ..self }
+    }
+
+    /// Send the timeout of every request in `TIMEOUT_HEADER`, so servers can stop waiting with the client
+    ///
+    /// This is on by default; disable it for servers that shouldn't learn the client's timeouts.
+    pub fn deadline_header(self, deadline_header: bool) -> HyperClientBuilder<C> {
+        HyperClientBuilder { deadline_header, ..self }
+    }
+
+    /// Run the given hook on every request right before it is sent, e.g. to sign it
+    pub fn request_hook<F>(self, hook: F) -> HyperClientBuilder<C>
+            where F: Fn(&mut Request<Body>) + Send + Sync + 'static {
+        HyperClientBuilder { request_hook: Some(RequestHook::new(hook)), ..self }
+    }
+
+    /// Fail responses with bodies larger than the given size in bytes with a `resource_exhausted` error
+    pub fn max_response_size(self, max_response_size: usize) -> HyperClientBuilder<C> {
+        HyperClientBuilder { max_response_size: Some(max_response_size), ..self }
+    }
+
+    /// Send the given `User-Agent` with every request that doesn't set one
+    pub fn user_agent(self, user_agent: HeaderValue) -> HyperClientBuilder<C> {
+        HyperClientBuilder { user_agent: Some(user_agent), ..self }
+    }
+
+    /// Set how long idle pooled connections are kept alive, 90 seconds by default
+    ///
+    /// `None` keeps them until the server closes them.
+    pub fn pool_idle_timeout(self, pool_idle_timeout: Option<Duration>) -> HyperClientBuilder<C> {
+        HyperClientBuilder { pool_idle_timeout, ..self }
+    }
+
+    /// Set the maximum number of idle pooled connections per host, unlimited by default
+    pub fn pool_max_idle_per_host(self, pool_max_idle_per_host: usize) -> HyperClientBuilder<C> {
+        HyperClientBuilder { pool_max_idle_per_host, ..self }
+    }
+
+    /// Use the given connector, e.g. a TLS connector for HTTPS
+    pub fn connector<D>(self, connector: D) -> HyperClientBuilder<D> {
+        HyperClientBuilder {
+            connector,
+            root_url: self.root_url,
+            timeout: self.timeout,
+            retry_policy: self.retry_policy,
+            default_headers: self.default_headers,
+            #[cfg(feature = "compression")]
+            gzip: self.gzip,
+            http2_only: self.http2_only,
+            deadline_header: self.deadline_header,
+            request_hook: self.request_hook,
+            max_response_size: self.max_response_size,
+            user_agent: self.user_agent,
+            pool_idle_timeout: self.pool_idle_timeout,
+            pool_max_idle_per_host: self.pool_max_idle_per_host,
+        }
+    }
+}
+
+impl<C> HyperClientBuilder<C> where C: Connect + 'static, C::Future: 'static {
+    /// Build the client
+    pub fn build(self) -> HyperClient<C> {
+        HyperClient {
+            client: Client::builder()
+                .http2_only(self.http2_only)
+                .keep_alive_timeout(self.pool_idle_timeout)
+                .max_idle_per_host(self.pool_max_idle_per_host)
+                .build(self.connector),
+            root_url: self.root_url.trim_end_matches('/').to_string(),
+            timeout: self.timeout,
+            default_headers: self.default_headers,
+            retry_policy: self.retry_policy,
+            #[cfg(feature = "compression")]
+            gzip: self.gzip,
+            http2_only: self.http2_only,
+            deadline_header: self.deadline_header,
+            request_hook: self.request_hook,
+            max_response_size: self.max_response_size,
+            user_agent: self.user_agent,
+        }
+    }
+}
+
+impl<C> Clone for HyperClient<C> {
+    fn clone(&self) -> HyperClient<C> {
+        HyperClient { client: self.client.clone(), root_url: self.root_url.clone(), timeout: self.timeout,
+            default_headers: self.default_headers.clone(), retry_policy: self.retry_policy.clone(),
+            #[cfg(feature = "compression")]
+            gzip: self.gzip,
+            http2_only: self.http2_only, deadline_header: self.deadline_header,
+            request_hook: self.request_hook.clone(), max_response_size: self.max_response_size,
+            user_agent: self.user_agent.clone() }
+    }
 }
 
-impl HyperClient {
+impl HyperClient<HttpConnector> {
+    /// Create a builder for a plain HTTP client
+    pub fn builder() -> HyperClientBuilder<HttpConnector> {
+        HyperClientBuilder::new()
+    }
+}
+
+impl<C> HyperClient<C> where C: Connect + 'static, C::Future: 'static {
     /// Create a new client wrapper for the given client and root using protobuf
-    pub fn new(client: Client<HttpConnector, Body>, root_url: &str) -> HyperClient {
+    pub fn new(client: Client<C, Body>, root_url: &str) -> HyperClient<C> {
         HyperClient {
             client,
-            root_url: root_url.trim_right_matches('/').to_string(),
+            root_url: root_url.trim_end_matches('/').to_string(),
+            timeout: None,
+            default_headers: HeaderMap::new(),
+            retry_policy: None,
+            #[cfg(feature = "compression")]
+            gzip: false,
+            http2_only: false,
+            deadline_header: true,
+            request_hook: None,
+            max_response_size: None,
+            user_agent: Some(HeaderValue::from_static(DEFAULT_USER_AGENT)),
         }
     }
 
+    /// Fail every request that takes longer than the given duration with `ProstTwirpError::Timeout`
+    ///
+    /// Timeouts require the request to be run within a tokio runtime.
+    pub fn with_timeout(self, timeout: Duration) -> HyperClient<C> {
+        HyperClient { timeout: Some(timeout), ..self }
+    }
+
+    /// Send the timeout of every request in `TIMEOUT_HEADER`, so servers can stop waiting with the client
+    ///
+    /// This is on by default; disable it for servers that shouldn't learn the client's timeouts.
+    pub fn with_deadline_header(self, deadline_header: bool) -> HyperClient<C> {
+        HyperClient { deadline_header, ..self }
+    }
+
+    /// Run the given hook on every request right before it is sent, e.g. to sign it
+    pub fn with_request_hook<F>(self, hook: F) -> HyperClient<C>
+            where F: Fn(&mut Request<Body>) + Send + Sync + 'static {
+        HyperClient { request_hook: Some(RequestHook::new(hook)), ..self }
+    }
+
+    /// Fail responses with bodies larger than the given size in bytes with a `resource_exhausted` error
+    ///
+    /// The limit applies both to the body as sent and to the body once decompressed.
+    pub fn with_max_response_size(self, max_response_size: usize) -> HyperClient<C> {
+        HyperClient { max_response_size: Some(max_response_size), ..self }
+    }
+
+    /// Send the given `User-Agent` with every request that doesn't set one
+    pub fn with_user_agent(self, user_agent: HeaderValue) -> HyperClient<C> {
+        HyperClient { user_agent: Some(user_agent), ..self }
+    }
+
+    /// Add the given headers to every request, e.g. for `Authorization`
+    pub fn with_default_headers(self, default_headers: HeaderMap<HeaderValue>) -> HyperClient<C> {
+        HyperClient { default_headers, ..self }
+    }
+
+    /// Gzip every request body and accept gzipped responses
+    #[cfg(feature = "compression")]
+    pub fn with_gzip(self, gzip: bool) -> HyperClient<C> {
+        HyperClient { gzip, ..self }
+    }
+
     /// Invoke the given request for the given path and return a boxed future result
+    ///
+    /// This retries the request if the client has a retry policy.
     pub fn go<I, O>(&self, path: &str, req: ServiceRequest<I>) -> PTRes<O>
+            where I: Message + Default + Clone + 'static, O: Message + Default + 'static {
+        instrument_client(path, || match self.retry_policy {
+            Some(ref policy) => self.go_with_retry(path, req, policy.clone()),
+            None => self.go_timeout(path, req, self.timeout),
+        })
+    }
+
+    /// Invoke the given request like `go`, overriding the client's timeout
+    pub fn go_with_timeout<I, O>(&self, path: &str, req: ServiceRequest<I>, timeout: Duration) -> PTRes<O>
             where I: Message + Default + 'static, O: Message + Default + 'static {
-        // Build the URI
-        let uri = format!("{}/{}", self.root_url, path.trim_left_matches('/')).parse().unwrap();
+        instrument_client(path, || self.go_timeout(path, req, Some(timeout)))
+    }
 
-        // Build the request
-        let mut hyper_req = match req.to_hyper_proto() {
+    /// Post the given bytes to the given path as they are, and return the raw response
+    ///
+    /// This is `go` without any protobuf encoding or decoding, e.g. for tools sending hand-crafted
+    /// JSON; the request headers, such as `Content-Type`, are sent as given. The response is returned
+    /// whatever its status, and `to_proto` or `to_proto_auto` turn error statuses into errors. The
+    /// client's timeout applies, but not its retry policy.
+    pub fn go_raw(&self, path: &str, req: ServiceRequest<Vec<u8>>) -> PTRes<Vec<u8>> {
+        instrument_client(path, || self.go_raw_timeout(path, req, self.timeout))
+    }
+
+    /// Invoke the given request like `go`, encoding it and decoding its response with the given codec
+    ///
+    /// Like `go_raw`, the client's timeout applies, but not its retry policy.
+    pub fn go_codec<I, O>(&self, path: &str, req: ServiceRequest<I>, codec: Arc<dyn Codec>) -> PTRes<O>
+            where I: Message + Default + serde::Serialize + 'static, O: Message + Default + DeserializeOwned + 'static {
+        let req = match req.to_codec_raw(&*codec) {
+            Ok(req) => req,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        Box::new(self.go_raw(path, req).and_then(move |resp| resp.to_proto_codec(&*codec)))
+    }
+
+    /// Post already serialized protobuf bytes to the given path, and decode the response like `go`
+    ///
+    /// This skips encoding, e.g. to replay or benchmark the same request body many times. The
+    /// request headers are sent as given, and like `go_raw`, the client's timeout applies, but not
+    /// its retry policy.
+    pub fn go_prepared<O: Message + Default + 'static>(&self, path: &str, req: ServiceRequest<Vec<u8>>) -> PTRes<O> {
+        instrument_client(path, || Box::new(self.go_raw_timeout(path, req, self.timeout).and_then(|resp| resp.to_proto())))
+    }
+
+    /// The URL requests for the given path are sent to
+    ///
+    /// The root URL is a base: its scheme, authority and path are kept, and the path, e.g.
+    /// `/twirp/pkg.Svc/Method` including any generator prefix, is appended to it with exactly one
+    /// slash in between. So `https://host/api` and `https://host/api/` both give
+    /// `https://host/api/twirp/pkg.Svc/Method`. A query string in the root URL stays at the end.
+    pub fn url(&self, path: &str) -> String {
+        let (base, query) = match self.root_url.find('?') {
+            Some(i) => self.root_url.split_at(i),
+            None => (self.root_url.as_str(), ""),
+        };
+        format!("{}/{}{}", base.trim_end_matches('/'), path.trim_start_matches('/'), query)
+    }
+
+    /// Invoke the given request like `go`, retrying it according to the given policy
+    ///
+    /// The input is cloned for every attempt, so it must implement `Clone`; prost messages always do.
+    pub fn go_with_retry<I, O>(&self, path: &str, req: ServiceRequest<I>, policy: RetryPolicy) -> PTRes<O>
+            where I: Message + Default + Clone + 'static, O: Message + Default + 'static {
+        type AttemptFuture<O> = Box<dyn Future<Item=future::Loop<ServiceResponse<O>, u32>, Error=ProstTwirpError> + Send>;
+
+        let client = self.clone();
+        let path = path.to_string();
+        Box::new(future::loop_fn(1, move |attempt| {
+            let policy = policy.clone();
+            client.go_timeout(&path, req.clone(), client.timeout).then(move |res| -> AttemptFuture<O> {
+                match res {
+                    Ok(resp) => Box::new(future::ok(future::Loop::Break(resp))),
+                    Err(err) => if attempt < policy.max_attempts && policy.is_retryable(&err) {
+                        // If the timer fails, give up with the original error
+                        Box::new(Delay::new(Instant::now() + policy.delay(attempt)).then(move |res| match res {
+                            Ok(_) => Ok(future::Loop::Continue(attempt + 1)),
+                            Err(_) => Err(err),
+                        }))
+                    } else {
+                        Box::new(future::err(err))
+                    }
+                }
+            })
+        }))
+    }
+
+    fn go_timeout<I, O>(&self, path: &str, req: ServiceRequest<I>, timeout: Option<Duration>) -> PTRes<O>
+            where I: Message + Default + 'static, O: Message + Default + 'static {
+        let raw = match req.to_proto_raw() {
             Err(err) => return Box::new(future::err(err)),
             Ok(v) => v
         };
-        *hyper_req.uri_mut() = uri;
+        Box::new(self.go_raw_timeout(path, raw, timeout).and_then(|resp| resp.to_proto()))
+    }
+
+    fn go_raw_timeout(&self, path: &str, req: ServiceRequest<Vec<u8>>, timeout: Option<Duration>) -> PTRes<Vec<u8>> {
+        let hyper_req = match self.build_raw_request(path, req, timeout) {
+            Err(err) => return Box::new(future::err(err)),
+            Ok(v) => v
+        };
+
+        count_body_bytes("twirp_client_request_bytes", path, hyper_req.headers().get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()).unwrap_or(0));
 
         // Run the request and map the response
-        Box::new(self.client.request(hyper_req).
+        let max_response_size = self.max_response_size;
+        let path = path.to_string();
+        let resp = self.client.request(hyper_req).
             map_err(ProstTwirpError::HyperError).
-            and_then(ServiceResponse::from_hyper_proto))
+            and_then(move |resp| ServiceResponse::from_hyper_raw_with_limit(resp, max_response_size)).
+            and_then(move |resp| {
+                count_body_bytes("twirp_client_response_bytes", &path, resp.output.len());
+                resp.decompress_with_limit(max_response_size)
+            });
+
+        match timeout {
+            // Timer errors (e.g. no timer in the runtime) are reported as timeouts too
+            Some(timeout) => Box::new(Timeout::new(resp, timeout).
+                map_err(|err| err.into_inner().unwrap_or(ProstTwirpError::Timeout))),
+            None => Box::new(resp),
+        }
+    }
+
+    #[cfg(test)]
+    fn build_request<I>(&self, path: &str, req: ServiceRequest<I>, timeout: Option<Duration>) -> Result<Request<Body>, ProstTwirpError>
+            where I: Message + Default + 'static {
+        self.build_raw_request(path, req.to_proto_raw()?, timeout)
+    }
+
+    fn build_raw_request(&self, path: &str, raw: ServiceRequest<Vec<u8>>, timeout: Option<Duration>) -> Result<Request<Body>, ProstTwirpError> {
+        // Build the URI
+        let uri = self.url(path);
+        let uri = uri.parse().map_err(|_| ProstTwirpError::InvalidUri(uri))?;
+
+        // Build the request
+        #[cfg(feature = "compression")]
+        let raw = if self.gzip { raw.compress() } else { raw };
+        let mut hyper_req = raw.to_hyper_raw()?;
+        *hyper_req.uri_mut() = uri;
+        if self.http2_only {
+            *hyper_req.version_mut() = Version::HTTP_2;
+        }
+        if let (true, Some(timeout)) = (self.deadline_header, timeout) {
+            hyper_req.headers_mut().insert(TIMEOUT_HEADER, HeaderValue::from(timeout.as_millis() as u64));
+        }
+
+        // Add default headers that the request doesn't set itself
+        for name in self.default_headers.keys() {
+            if !hyper_req.headers().contains_key(name) {
+                for value in self.default_headers.get_all(name) {
+                    hyper_req.headers_mut().append(name, value.clone());
+                }
+            }
+        }
+        if let Some(ref user_agent) = self.user_agent {
+            hyper_req.headers_mut().entry(USER_AGENT).expect("valid header name").or_insert_with(|| user_agent.clone());
+        }
+
+        if let Some(ref hook) = self.request_hook {
+            hook.run(&mut hyper_req);
+        }
+        Ok(hyper_req)
+    }
+}
+
+/// A client that invokes RPCs by path, as used by generated clients
+///
+/// This is implemented by `HyperClient`, and by `MockClient` with the `test-util` feature.
+pub trait TwirpClient: Send + Sync {
+    /// Invoke the given request for the given path and return a boxed future result
+    fn go<I, O>(&self, path: &str, req: ServiceRequest<I>) -> PTRes<O>
+        where I: Message + Default + Clone + 'static, O: Message + Default + 'static;
+}
+
+impl<C> TwirpClient for HyperClient<C> where C: Connect + 'static, C::Future: 'static {
+    fn go<I, O>(&self, path: &str, req: ServiceRequest<I>) -> PTRes<O>
+            where I: Message + Default + Clone + 'static, O: Message + Default + 'static {
+        HyperClient::go(self, path, req)
+    }
+}
+
+/// Run a client call within a `twirp.client` tracing span, like `instrument_server`
+fn instrument_client<O, F>(path: &str, call: F) -> PTRes<O> where O: Send + 'static, F: FnOnce() -> PTRes<O> {
+    #[cfg(feature = "tracing")]
+    {
+        use tracing_futures::Instrument;
+
+        let span = tracing::info_span!("twirp.client", path = path,
+            status = tracing::field::Empty, error = tracing::field::Empty);
+        let record = span.clone();
+        let resp = span.in_scope(call).then(move |res| {
+            match res {
+                Ok(ref resp) => { record.record("status", resp.status.as_u16()); }
+                Err(ref err) => {
+                    if let ProstTwirpError::AfterBodyError { status: Some(status), .. } = *err {
+                        record.record("status", status.as_u16());
+                    }
+                    record.record("error", tracing::field::display(err));
+                }
+            }
+            res
+        });
+        Box::new(resp.instrument(span))
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = path;
+        call()
     }
 }
 
+#[cfg(test)]
+mod hyper_client_tests {
+    use super::*;
+
+    #[test]
+    fn invalid_uri() {
+        let client = HyperClient::new(Client::new(), "http://local host");
+        let res: Result<ServiceResponse<()>, _> = client.go("/twirp/pkg.Svc/Method", ServiceRequest::new(())).wait();
+        match res {
+            Err(ProstTwirpError::InvalidUri(uri)) => assert_eq!(uri, "http://local host/twirp/pkg.Svc/Method"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        // Accept connections but never reply to them
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let root_url = format!("http://{}", listener.local_addr().unwrap());
+
+        let client = HyperClient::new(Client::new(), &root_url).with_timeout(Duration::from_millis(50));
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let res: Result<ServiceResponse<()>, _> =
+            runtime.block_on(client.go("/twirp/pkg.Svc/Method", ServiceRequest::new(())));
+        match res {
+            Err(ProstTwirpError::Timeout) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        drop(listener);
+    }
+
+    #[test]
+    fn root_url_paths() {
+        let uri = |root_url: &str, path: &str| {
+            let client = HyperClient::new(Client::new(), root_url);
+            client.build_request(path, ServiceRequest::new(()), None).unwrap().uri().to_string()
+        };
+        let path = "/twirp/pkg.Svc/Method";
+        assert_eq!(uri("https://host", path), "https://host/twirp/pkg.Svc/Method");
+        assert_eq!(uri("https://host/", path), "https://host/twirp/pkg.Svc/Method");
+        assert_eq!(uri("https://host:8443/api", path), "https://host:8443/api/twirp/pkg.Svc/Method");
+        assert_eq!(uri("https://host/api/", path), "https://host/api/twirp/pkg.Svc/Method");
+        assert_eq!(uri("https://host/api/v1", "twirp/pkg.Svc/Method"), "https://host/api/v1/twirp/pkg.Svc/Method");
+        assert_eq!(uri("https://host/api/?key=abc", path), "https://host/api/twirp/pkg.Svc/Method?key=abc");
+
+        // Root URLs set directly are joined the same way
+        let mut client = HyperClient::new(Client::new(), "https://host");
+        client.root_url = "https://host/rpc//".to_string();
+        assert_eq!(client.url("//twirp/pkg.Svc/Method"), "https://host/rpc/twirp/pkg.Svc/Method");
+    }
+
+    #[test]
+    fn default_headers() {
+        let mut default_headers = HeaderMap::new();
+        default_headers.insert("authorization", HeaderValue::from_static("Bearer default"));
+        default_headers.insert("x-request-id", HeaderValue::from_static("default"));
+        let client = HyperClient::new(Client::new(), "http://localhost").with_default_headers(default_headers);
+
+        let mut req = ServiceRequest::new(());
+        req.headers.insert("x-request-id", HeaderValue::from_static("custom"));
+        let hyper_req = client.build_request("/twirp/pkg.Svc/Method", req, None).unwrap();
+        assert_eq!(hyper_req.headers()["authorization"], "Bearer default");
+        assert_eq!(hyper_req.headers()["x-request-id"], "custom");
+    }
+
+    #[test]
+    fn user_agent() {
+        let client = HyperClient::new(Client::new(), "http://localhost");
+        let req = client.build_request("/twirp/pkg.Svc/Method", ServiceRequest::new(()), None).unwrap();
+        assert_eq!(req.headers()[USER_AGENT], DEFAULT_USER_AGENT);
+        assert!(DEFAULT_USER_AGENT.starts_with("twirp-rs/"));
+
+        let client = client.with_user_agent(HeaderValue::from_static("hat-cli/2.0"));
+        let req = client.build_request("/twirp/pkg.Svc/Method", ServiceRequest::new(()), None).unwrap();
+        assert_eq!(req.headers()[USER_AGENT], "hat-cli/2.0");
+
+        let mut req = ServiceRequest::new(());
+        req.headers.insert(USER_AGENT, HeaderValue::from_static("custom"));
+        let req = client.build_request("/twirp/pkg.Svc/Method", req, None).unwrap();
+        assert_eq!(req.headers()[USER_AGENT], "custom");
+
+        let client = HyperClient::builder().root_url("http://localhost").user_agent(HeaderValue::from_static("hat-cli/3.0")).build();
+        let req = client.build_request("/twirp/pkg.Svc/Method", ServiceRequest::new(()), None).unwrap();
+        assert_eq!(req.headers()[USER_AGENT], "hat-cli/3.0");
+    }
+
+    #[test]
+    fn request_hook() {
+        let client = HyperClient::builder().root_url("http://localhost")
+            .request_hook(|req| {
+                let signature = format!("{} {}", req.uri().path(), req.headers()[CONTENT_LENGTH].to_str().unwrap());
+                req.headers_mut().insert("x-signature", HeaderValue::from_str(&signature).unwrap());
+            })
+            .build();
+        // The hook sees the final request, e.g. the length of the encoded input
+        let req = client.build_request("/twirp/pkg.Svc/Method", ServiceRequest::new(vec![1u8, 2, 3]), None).unwrap();
+        assert_eq!(req.headers()["x-signature"], "/twirp/pkg.Svc/Method 5");
+        assert!(HyperClient::new(Client::new(), "http://localhost").request_hook.is_none());
+    }
+
+    #[test]
+    fn deadline_header() {
+        let client = HyperClient::new(Client::new(), "http://localhost");
+        let timeout = Some(Duration::from_secs(2));
+        let req = client.clone().with_deadline_header(false).build_request("/twirp/pkg.Svc/Method", ServiceRequest::new(()), timeout).unwrap();
+        assert!(!req.headers().contains_key(TIMEOUT_HEADER));
+
+        assert!(!client.build_request("/twirp/pkg.Svc/Method", ServiceRequest::new(()), None).unwrap()
+            .headers().contains_key(TIMEOUT_HEADER));
+        let req = client.build_request("/twirp/pkg.Svc/Method", ServiceRequest::new(()), timeout).unwrap();
+        assert_eq!(req.headers()[TIMEOUT_HEADER], "2000");
+
+        let before = Instant::now();
+        let req = ServiceRequest::from_hyper_raw(req).wait().unwrap();
+        let deadline = req.deadline().unwrap();
+        assert!(deadline >= before + Duration::from_secs(2) && deadline <= Instant::now() + Duration::from_secs(2));
+        assert!(req.time_remaining().unwrap() <= Duration::from_secs(2));
+        assert_eq!(ServiceRequest::new(()).deadline(), None);
+
+        // Timeouts past what an `Instant` can represent on some platforms mean no deadline rather than a panic
+        let hyper_req = Request::builder().header(TIMEOUT_HEADER, u64::MAX.to_string()).body(Body::empty()).unwrap();
+        let req = ServiceRequest::from_hyper_raw(hyper_req).wait().unwrap();
+        assert!(req.deadline().is_none_or(|deadline| deadline > Instant::now()));
+    }
+
+    #[test]
+    fn builder() {
+        let client = HyperClient::builder()
+            .root_url("http://localhost/")
+            .timeout(Duration::from_secs(1))
+            .retry_policy(RetryPolicy::default())
+            .build();
+        assert_eq!(client.root_url, "http://localhost");
+        assert_eq!(client.timeout, Some(Duration::from_secs(1)));
+        assert_eq!(client.retry_policy.unwrap().max_attempts, 3);
+    }
+
+    #[test]
+    fn http2_only() {
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).http2_only(true)
+            .serve(|| hyper::service::service_fn_ok(|_| Response::new(Body::empty())));
+        let client = HyperClient::builder().root_url(&format!("http://{}", server.local_addr())).http2_only(true).build();
+
+        let req = client.build_request("/twirp/pkg.Svc/Method", ServiceRequest::new(()), None).unwrap();
+        assert_eq!(req.version(), Version::HTTP_2);
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+        let resp: ServiceResponse<()> = runtime.block_on(client.go("/twirp/pkg.Svc/Method", ServiceRequest::new(()))).unwrap();
+        assert_eq!(resp.version, Version::HTTP_2);
+    }
+
+    #[test]
+    fn go_raw() {
+        // Echo non-empty bodies back, and fail empty ones
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(|| hyper::service::service_fn(|req: Request<Body>| {
+                ServiceRequest::from_hyper_raw(req).then(|req| Ok::<_, hyper::Error>(match req {
+                    Ok(ref req) if !req.input.is_empty() => ServiceResponse::new(req.input.clone()).to_hyper_raw().unwrap(),
+                    _ => TwirpError::invalid_argument("Empty body").to_hyper_resp().unwrap(),
+                }))
+            }));
+        let client = HyperClient::new(Client::new(), &format!("http://{}", server.local_addr()));
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+        let mut req = ServiceRequest::new(b"{\"inches\":10}".to_vec());
+        req.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let resp = runtime.block_on(client.go_raw("/twirp/pkg.Svc/Method", req)).unwrap();
+        assert_eq!(resp.status, StatusCode::OK);
+        assert_eq!(resp.output, b"{\"inches\":10}".to_vec());
+
+        // Error statuses are responses until decoded
+        let resp = runtime.block_on(client.go_raw("/twirp/pkg.Svc/Method", ServiceRequest::new(vec![]))).unwrap();
+        assert_eq!(resp.status, StatusCode::BAD_REQUEST);
+        let err = resp.to_proto::<()>().unwrap_err();
+        assert_eq!(err.twirp_code(), Some("invalid_argument"));
+
+        // Prepared bodies are sent as they are, and their responses decoded
+        let body = ServiceRequest::new("fedora".to_string()).to_proto_raw().unwrap();
+        for _ in 0..2 {
+            let resp = runtime.block_on(client.go_prepared::<String>("/twirp/pkg.Svc/Method", body.clone())).unwrap();
+            assert_eq!(resp.output, "fedora");
+        }
+        let err = runtime.block_on(client.go_prepared::<String>("/twirp/pkg.Svc/Method", ServiceRequest::new(vec![]))).unwrap_err();
+        assert_eq!(err.twirp_code(), Some("invalid_argument"));
+    }
+
+    #[test]
+    fn max_response_size() {
+        // Stream a large body without a Content-Length, and a small one with it
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(|| hyper::service::service_fn_ok(|req: Request<Body>| match req.uri().path() {
+                "/twirp/pkg.Svc/Large" => {
+                    let chunks = (0..64).map(|_| Ok::<_, hyper::Error>(vec![1u8; 1024]));
+                    Response::new(Body::wrap_stream(futures::stream::iter_result(chunks)))
+                }
+                _ => ServiceResponse::new(vec![1u8; 100]).to_hyper_raw().unwrap(),
+            }));
+        let client = HyperClient::new(Client::new(), &format!("http://{}", server.local_addr()))
+            .with_max_response_size(4096);
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+        let resp = runtime.block_on(client.go_raw("/twirp/pkg.Svc/Small", ServiceRequest::new(vec![]))).unwrap();
+        assert_eq!(resp.output.len(), 100);
+        let err = runtime.block_on(client.go_raw("/twirp/pkg.Svc/Large", ServiceRequest::new(vec![]))).unwrap_err();
+        assert_eq!(err.to_string(), "twirp error: resource_exhausted: Response body too large");
+
+        let client = client.with_max_response_size(10);
+        let err = runtime.block_on(client.go_raw("/twirp/pkg.Svc/Small", ServiceRequest::new(vec![]))).unwrap_err();
+        assert!(err.is_twirp_code("resource_exhausted"));
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn max_decompressed_response_size() {
+        // Reply with a megabyte of zeros gzipped into about a kilobyte
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(|| hyper::service::service_fn_ok(|_| {
+                ServiceResponse::new(vec![0u8; 1 << 20]).compress_for(Some(&HeaderValue::from_static("gzip")))
+                    .to_hyper_raw().unwrap()
+            }));
+        let client = HyperClient::new(Client::new(), &format!("http://{}", server.local_addr()))
+            .with_max_response_size(4096);
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(server.map_err(|err| panic!("server failed: {}", err)));
+        let err = runtime.block_on(client.go_raw("/twirp/pkg.Svc/Method", ServiceRequest::new(vec![]))).unwrap_err();
+        assert_eq!(err.to_string(), "twirp error: resource_exhausted: Response body too large");
+
+        let client = client.with_max_response_size(1 << 20);
+        let resp = runtime.block_on(client.go_raw("/twirp/pkg.Svc/Method", ServiceRequest::new(vec![]))).unwrap();
+        assert_eq!(resp.output.len(), 1 << 20);
+    }
+
+    #[test]
+    fn retry_policy() {
+        let policy = RetryPolicy::default();
+        assert!(policy.is_retryable(&TwirpError::unavailable("down").into()));
+        assert!(policy.is_retryable(&ProstTwirpError::Timeout));
+        assert!(!policy.is_retryable(&TwirpError::not_found("no such hat").into()));
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+    }
+}